@@ -0,0 +1,237 @@
+use crate::auth::{ClusterKey, TAG_SIZE};
+use crate::error::Error;
+use crate::utils::batch_name;
+use crate::{NodeAddress, BATCH_ID_SIZE};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// Leading byte of an acknowledgement, data packets start with 0
+pub const ACK_KIND: u8 = 1;
+
+/// Size of a signed acknowledgement: kind, batch id, receiver port, decode time in milliseconds,
+/// number of packets used and the authentication tag
+const ACK_SIZE: usize = 1 + BATCH_ID_SIZE + 2 + 8 + 4 + TAG_SIZE;
+
+/// Sent by a receiver to the sender of a batch once it has decoded it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ack {
+    pub batch_id: [u8; BATCH_ID_SIZE],
+    /// Port the receiver listens on, identifying it along with the source address of the ack
+    pub receiver_port: u16,
+    /// Time between the first packet of the batch and its decoding
+    pub decode_time: Duration,
+    /// Number of unique symbols the decoder needed
+    pub packets_used: u32,
+}
+
+impl Ack {
+    /// It serializes and signs the acknowledgement
+    pub fn to_bytes(&self, key: &ClusterKey) -> Vec<u8> {
+        let mut message = Vec::with_capacity(ACK_SIZE);
+        message.push(ACK_KIND);
+        message.extend_from_slice(&self.batch_id);
+        message.extend_from_slice(&self.receiver_port.to_be_bytes());
+        message.extend_from_slice(&(self.decode_time.as_millis() as u64).to_be_bytes());
+        message.extend_from_slice(&self.packets_used.to_be_bytes());
+        key.sign(message)
+    }
+
+    /// It verifies and parses a signed acknowledgement
+    pub fn from_bytes(bytes: &[u8], key: &ClusterKey) -> Result<Ack, Error> {
+        if bytes.len() != ACK_SIZE || bytes[0] != ACK_KIND {
+            return Err(Error::MalformedPacket(format!(
+                "ack of {} bytes, expected {}",
+                bytes.len(),
+                ACK_SIZE
+            )));
+        }
+        let message = key
+            .verify(bytes)
+            .ok_or_else(|| Error::MalformedPacket("ack signature mismatch".to_string()))?;
+
+        let (batch_id, rest) = message[1..].split_at(BATCH_ID_SIZE);
+        let (receiver_port, rest) = rest.split_at(2);
+        let (decode_time, packets_used) = rest.split_at(8);
+        Ok(Ack {
+            batch_id: to_array(batch_id),
+            receiver_port: u16::from_be_bytes(to_array(receiver_port)),
+            decode_time: Duration::from_millis(u64::from_be_bytes(to_array(decode_time))),
+            packets_used: u32::from_be_bytes(to_array(packets_used)),
+        })
+    }
+}
+
+/// It copies a field of a message whose length has already been checked
+fn to_array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut array = [0; N];
+    array.copy_from_slice(bytes);
+    array
+}
+
+/// It sends the acknowledgements of a receiver
+pub struct AckSender {
+    socket: std::net::UdpSocket,
+    key: ClusterKey,
+    port: u16,
+}
+
+impl AckSender {
+    /// Arguments:
+    ///
+    /// * `key`: The cluster key signing the acknowledgements.
+    /// * `port`: The port the receiver listens on.
+    pub fn new(key: ClusterKey, port: u16) -> Result<Self, Error> {
+        Ok(AckSender {
+            socket: std::net::UdpSocket::bind("0.0.0.0:0")?,
+            key,
+            port,
+        })
+    }
+
+    /// It acknowledges a decoded batch to the address its packets came from
+    pub fn send(
+        &self,
+        batch_id: [u8; BATCH_ID_SIZE],
+        decode_time: Duration,
+        packets_used: usize,
+        to: SocketAddr,
+    ) -> Result<(), Error> {
+        let ack = Ack {
+            batch_id,
+            receiver_port: self.port,
+            decode_time,
+            packets_used: packets_used as u32,
+        };
+        self.socket.send_to(&ack.to_bytes(&self.key), to)?;
+        Ok(())
+    }
+}
+
+/// It waits for the receivers to acknowledge the batches sent from `socket`, then reports the
+/// completion latency of every receiver and the receivers that never acknowledged
+///
+/// Arguments:
+///
+/// * `socket`: The socket the batches were sent from, where the acknowledgements arrive.
+/// * `batch_ids`: The batches sent from the socket.
+/// * `receivers`: The nodes handed packets of the batches directly. Only the receivers among them
+///   acknowledge, relays do not decode and observers do not acknowledge.
+/// * `key`: The cluster key verifying the acknowledgements.
+/// * `started`: When the first packet was sent.
+/// * `timeout`: How long to wait for the acknowledgements, counted from `started`.
+pub async fn collect_acks(
+    socket: &UdpSocket,
+    batch_ids: &[[u8; BATCH_ID_SIZE]],
+    receivers: &[NodeAddress],
+    key: &ClusterKey,
+    started: Instant,
+    timeout: Duration,
+) {
+    let expected: Vec<&NodeAddress> = receivers.iter().filter(|n| n.role.acknowledges()).collect();
+    // Every (batch, receiver) acknowledged so far
+    let mut acked: HashSet<([u8; BATCH_ID_SIZE], usize)> = HashSet::new();
+    let mut buf = [0; ACK_SIZE + 1];
+    let deadline = tokio::time::Instant::from_std(started + timeout);
+
+    while acked.len() < batch_ids.len() * expected.len() {
+        let (len, from) = match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await
+        {
+            Ok(Ok(received)) => received,
+            Ok(Err(e)) => {
+                println!("Failed to receive ack: {}", e);
+                continue;
+            }
+            Err(_) => break,
+        };
+        let ack = match Ack::from_bytes(&buf[..len], key) {
+            Ok(ack) => ack,
+            Err(e) => {
+                println!("Ignoring datagram from {}: {}", from, e);
+                continue;
+            }
+        };
+        if !batch_ids.contains(&ack.batch_id) {
+            continue;
+        }
+        let Some(node) = expected
+            .iter()
+            .position(|n| n.is_at(from.ip(), ack.receiver_port))
+        else {
+            println!(
+                "Batch: {}: Ack from unknown receiver {} port {}",
+                batch_name(&ack.batch_id),
+                from,
+                ack.receiver_port
+            );
+            continue;
+        };
+        if !acked.insert((ack.batch_id, node)) {
+            continue;
+        }
+        let latency = started.elapsed();
+        println!(
+            "Batch: {}: Acknowledged by {}:{}: after {:?}: decode time {:?}: packets used {}",
+            batch_name(&ack.batch_id),
+            from.ip(),
+            ack.receiver_port,
+            latency,
+            ack.decode_time,
+            ack.packets_used
+        );
+    }
+
+    for batch_id in batch_ids {
+        let missing: Vec<String> = expected
+            .iter()
+            .enumerate()
+            .filter(|(node, _)| !acked.contains(&(*batch_id, *node)))
+            .map(|(_, n)| format!("{}:{}", n.ip_addr, n.port))
+            .collect();
+        println!(
+            "Batch: {}: Receivers acknowledged: {}/{}: Never finished: [{}]",
+            batch_name(batch_id),
+            expected.len() - missing.len(),
+            expected.len(),
+            missing.join(", ")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ack() -> Ack {
+        Ack {
+            batch_id: [b'a'; BATCH_ID_SIZE],
+            receiver_port: 1235,
+            decode_time: Duration::from_millis(12),
+            packets_used: 5986,
+        }
+    }
+
+    #[test]
+    fn ack_round_trips() {
+        let key = ClusterKey::new("test");
+        assert_eq!(Ack::from_bytes(&ack().to_bytes(&key), &key).unwrap(), ack());
+    }
+
+    #[test]
+    fn ack_with_a_tampered_tag_or_field_is_rejected() {
+        let key = ClusterKey::new("test");
+        let bytes = ack().to_bytes(&key);
+
+        let mut tag = bytes.clone();
+        tag[ACK_SIZE - 1] ^= 1;
+        assert!(Ack::from_bytes(&tag, &key).is_err());
+
+        let mut port = bytes.clone();
+        port[1 + BATCH_ID_SIZE] ^= 1;
+        assert!(Ack::from_bytes(&port, &key).is_err());
+
+        assert!(Ack::from_bytes(&bytes, &ClusterKey::new("other")).is_err());
+        assert!(Ack::from_bytes(&bytes[..ACK_SIZE - 1], &key).is_err());
+    }
+}
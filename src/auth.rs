@@ -0,0 +1,38 @@
+use ring::hmac;
+
+/// Size of the authentication tag appended to every signed message
+pub const TAG_SIZE: usize = 32;
+
+/// Secret shared by every node of the cluster, used to sign and verify the control messages
+/// exchanged between nodes with HMAC-SHA256
+#[derive(Clone)]
+pub struct ClusterKey {
+    key: hmac::Key,
+}
+
+impl ClusterKey {
+    pub fn new(secret: &str) -> Self {
+        ClusterKey {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        }
+    }
+
+    /// It appends the authentication tag of `message` to it
+    pub fn sign(&self, mut message: Vec<u8>) -> Vec<u8> {
+        let tag = hmac::sign(&self.key, &message);
+        message.extend_from_slice(tag.as_ref());
+        message
+    }
+
+    /// It checks the authentication tag at the end of a signed message
+    ///
+    /// Returns:
+    ///
+    /// The message without its tag, if the tag is valid
+    pub fn verify<'a>(&self, signed: &'a [u8]) -> Option<&'a [u8]> {
+        let message_len = signed.len().checked_sub(TAG_SIZE)?;
+        let (message, tag) = signed.split_at(message_len);
+        hmac::verify(&self.key, message, tag).ok()?;
+        Some(message)
+    }
+}
@@ -0,0 +1,319 @@
+use crate::utils::{decode_packet, encode_with_symbol_size};
+use clap::Args;
+use nix::libc::c_long;
+use nix::sys::resource::{getrusage, UsageWho};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use raptorq::{Decoder, ObjectTransmissionInformation};
+use std::io;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+#[derive(Args, Debug)]
+pub struct BenchArguments {
+    #[clap(
+        value_name = "symbol-sizes",
+        long = "symbol-sizes",
+        help = "comma separated maximum symbol sizes in bytes",
+        use_value_delimiter = true,
+        default_value = "512,1176"
+    )]
+    pub symbol_sizes: Vec<u16>,
+
+    #[clap(
+        value_name = "payload-sizes",
+        long = "payload-sizes",
+        help = "comma separated payload sizes in bytes",
+        use_value_delimiter = true,
+        default_value = "65536,1048576,7038895"
+    )]
+    pub payload_sizes: Vec<usize>,
+
+    #[clap(
+        value_name = "repair-overheads",
+        long = "repair-overheads",
+        help = "comma separated repair symbols generated, as a fraction of the source symbols",
+        use_value_delimiter = true,
+        default_value = "0.1,0.5"
+    )]
+    pub repair_overheads: Vec<f64>,
+
+    #[clap(
+        value_name = "loss",
+        long = "loss",
+        help = "probability of dropping each encoded symbol before decoding",
+        default_value_t = 0.0
+    )]
+    pub loss: f64,
+
+    #[clap(
+        value_name = "iterations",
+        long = "iterations",
+        help = "number of runs averaged for each configuration",
+        default_value_t = 3
+    )]
+    pub iterations: usize,
+
+    #[clap(
+        value_name = "seed",
+        long = "seed",
+        help = "seed for the synthetic payloads and the symbol loss",
+        default_value_t = 0
+    )]
+    pub seed: u64,
+
+    #[clap(long = "csv", help = "print results as CSV instead of a table")]
+    pub csv: bool,
+
+    /// Set on the child process running a single configuration, which prints its row only
+    #[clap(long = "single-configuration", hide = true)]
+    pub single_configuration: bool,
+}
+
+/// Averaged measurements of a single benchmark configuration
+struct BenchResult {
+    symbol_size: u16,
+    payload_size: usize,
+    repair_overhead: f64,
+    source_symbols: u64,
+    symbols_sent: usize,
+    /// Average over the successful runs, `None` when every run failed
+    symbols_needed: Option<f64>,
+    failures: usize,
+    encode_mb_s: f64,
+    decode_mb_s: f64,
+    peak_rss_kb: c_long,
+}
+
+/// It runs the encoder and the receiver decoder path over synthetic payloads for every combination
+/// of symbol size, payload size and repair overhead, and prints the throughput and the number of
+/// symbols needed to decode compared with the source symbol count, the theoretical minimum. Every
+/// configuration runs in a child process, so that the peak resident memory it reports is its own
+/// rather than that of the largest configuration run before it.
+///
+/// Arguments:
+///
+/// * `options`: The bench subcommand arguments
+pub fn run_bench(options: BenchArguments) -> io::Result<()> {
+    if !(0.0..1.0).contains(&options.loss) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--loss must be in the range [0, 1)",
+        ));
+    }
+    if options.payload_sizes.contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--payload-sizes must all be positive",
+        ));
+    }
+    if options.symbol_sizes.contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--symbol-sizes must all be positive",
+        ));
+    }
+    if options.iterations == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--iterations must be positive",
+        ));
+    }
+
+    if options.single_configuration {
+        let mut rng = StdRng::seed_from_u64(options.seed);
+        let mut payload = vec![0_u8; options.payload_sizes[0]];
+        rng.fill_bytes(&mut payload);
+        let result = bench_configuration(
+            &payload,
+            options.symbol_sizes[0],
+            options.repair_overheads[0],
+            &options,
+            &mut rng,
+        );
+        print_result(&result, options.loss, options.csv);
+        return Ok(());
+    }
+
+    print_header(options.csv);
+    for &payload_size in &options.payload_sizes {
+        for &symbol_size in &options.symbol_sizes {
+            for &repair_overhead in &options.repair_overheads {
+                run_in_child(payload_size, symbol_size, repair_overhead, &options)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// It runs a single configuration in a child process, which prints its row to the standard output
+/// shared with this process
+fn run_in_child(
+    payload_size: usize,
+    symbol_size: u16,
+    repair_overhead: f64,
+    options: &BenchArguments,
+) -> io::Result<()> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .arg("bench")
+        .arg(format!("--payload-sizes={}", payload_size))
+        .arg(format!("--symbol-sizes={}", symbol_size))
+        .arg(format!("--repair-overheads={}", repair_overhead))
+        .arg(format!("--loss={}", options.loss))
+        .arg(format!("--iterations={}", options.iterations))
+        .arg(format!("--seed={}", options.seed))
+        .arg("--single-configuration");
+    if options.csv {
+        command.arg("--csv");
+    }
+    let status = command.status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "bench of {} bytes with symbols of {} bytes and repair overhead {} failed: {}",
+            payload_size, symbol_size, repair_overhead, status
+        )));
+    }
+    Ok(())
+}
+
+fn bench_configuration(
+    payload: &[u8],
+    symbol_size: u16,
+    repair_overhead: f64,
+    options: &BenchArguments,
+    rng: &mut StdRng,
+) -> BenchResult {
+    let config = ObjectTransmissionInformation::with_defaults(payload.len() as u64, symbol_size);
+    let source_symbols = (payload.len() as u64).div_ceil(config.symbol_size() as u64);
+    let blocks = config.source_blocks() as u64;
+    let repair_symbols = (source_symbols as f64 * repair_overhead).ceil() as u64;
+    let repair_per_block = repair_symbols.div_ceil(blocks) as u32;
+
+    let mut encode_time = Duration::ZERO;
+    let mut decode_time = Duration::ZERO;
+    let mut symbols_sent = 0;
+    let mut symbols_needed = 0;
+    let mut failures = 0;
+    for _ in 0..options.iterations {
+        let now = Instant::now();
+        let encoded = encode_with_symbol_size(payload, symbol_size, repair_per_block);
+        encode_time += now.elapsed();
+        symbols_sent = encoded.source.len() + encoded.repair.len();
+
+        let received: Vec<&Vec<u8>> = encoded
+            .source
+            .iter()
+            .chain(encoded.repair.iter())
+            .filter(|_| options.loss == 0.0 || !rng.gen_bool(options.loss))
+            .collect();
+
+        let now = Instant::now();
+        let mut decoder = Decoder::new(encoded.config);
+        let mut decoded = None;
+        for (index, packet) in received.iter().enumerate() {
+            if let Some(result) = decode_packet(&mut decoder, packet) {
+                decoded = Some((index + 1, result));
+                break;
+            }
+        }
+        decode_time += now.elapsed();
+
+        match decoded {
+            Some((needed, result)) if result == payload => symbols_needed += needed,
+            _ => failures += 1,
+        }
+    }
+
+    let succeeded = options.iterations - failures;
+    let megabytes = (payload.len() * options.iterations) as f64 / 1_000_000.0;
+    BenchResult {
+        symbol_size: config.symbol_size(),
+        payload_size: payload.len(),
+        repair_overhead,
+        source_symbols,
+        symbols_sent,
+        symbols_needed: (succeeded > 0).then(|| symbols_needed as f64 / succeeded as f64),
+        failures,
+        encode_mb_s: megabytes / encode_time.as_secs_f64(),
+        decode_mb_s: megabytes / decode_time.as_secs_f64(),
+        peak_rss_kb: peak_rss_kb(),
+    }
+}
+
+/// Peak resident set size of the process so far, in kilobytes. The process runs a single
+/// configuration, so this is the peak of that configuration.
+fn peak_rss_kb() -> c_long {
+    match getrusage(UsageWho::RUSAGE_SELF) {
+        // macOS reports ru_maxrss in bytes, Linux in kilobytes
+        Ok(usage) if cfg!(target_os = "macos") => usage.max_rss() / 1024,
+        Ok(usage) => usage.max_rss(),
+        Err(_) => 0,
+    }
+}
+
+fn print_header(csv: bool) {
+    if csv {
+        println!("symbol_size,payload_bytes,repair_overhead,loss,source_symbols,symbols_sent,symbols_needed,symbol_overhead,failures,encode_mb_s,decode_mb_s,peak_rss_kb");
+    } else {
+        println!(
+            "{:>7} {:>10} {:>7} {:>6} {:>8} {:>8} {:>10} {:>9} {:>6} {:>10} {:>10} {:>12}",
+            "symbol",
+            "payload",
+            "repair",
+            "loss",
+            "source",
+            "sent",
+            "needed",
+            "overhead",
+            "failed",
+            "enc MB/s",
+            "dec MB/s",
+            "peak RSS KB"
+        );
+    }
+}
+
+fn print_result(result: &BenchResult, loss: f64, csv: bool) {
+    // Rows where every run failed have no symbol count to report
+    let (needed, overhead) = match result.symbols_needed {
+        Some(needed) => (
+            format!("{:.1}", needed),
+            format!("{:.1}", needed - result.source_symbols as f64),
+        ),
+        None => ("-".to_string(), "-".to_string()),
+    };
+    if csv {
+        println!(
+            "{},{},{},{},{},{},{},{},{},{:.2},{:.2},{}",
+            result.symbol_size,
+            result.payload_size,
+            result.repair_overhead,
+            loss,
+            result.source_symbols,
+            result.symbols_sent,
+            needed,
+            overhead,
+            result.failures,
+            result.encode_mb_s,
+            result.decode_mb_s,
+            result.peak_rss_kb
+        );
+    } else {
+        println!(
+            "{:>7} {:>10} {:>7.2} {:>6.3} {:>8} {:>8} {:>10} {:>9} {:>6} {:>10.2} {:>10.2} {:>12}",
+            result.symbol_size,
+            result.payload_size,
+            result.repair_overhead,
+            loss,
+            result.source_symbols,
+            result.symbols_sent,
+            needed,
+            overhead,
+            result.failures,
+            result.encode_mb_s,
+            result.decode_mb_s,
+            result.peak_rss_kb
+        );
+    }
+}
@@ -0,0 +1,58 @@
+use crate::BATCH_ID_SIZE;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Ids of the batches decoded within the retention window, used to drop late packets of batches
+/// that are already reassembled. Ids are forgotten once they are older than the retention, or
+/// when more than `max_batches` are held, so memory stays flat however long the receiver runs.
+pub struct CompletedBatches {
+    ids: HashSet<[u8; BATCH_ID_SIZE]>,
+    /// Completion order of the ids, oldest first
+    window: VecDeque<(Instant, [u8; BATCH_ID_SIZE])>,
+    retention: Duration,
+    max_batches: usize,
+}
+
+impl CompletedBatches {
+    pub fn new(retention: Duration, max_batches: usize) -> Self {
+        CompletedBatches {
+            ids: HashSet::new(),
+            window: VecDeque::new(),
+            retention,
+            max_batches: max_batches.max(1),
+        }
+    }
+
+    /// Whether the batch was decoded within the retention window
+    pub fn contains(&self, batch_id: &[u8; BATCH_ID_SIZE]) -> bool {
+        self.ids.contains(batch_id)
+    }
+
+    /// It remembers a decoded batch, forgetting the oldest one if the window is full
+    pub fn insert(&mut self, batch_id: [u8; BATCH_ID_SIZE]) {
+        self.expire();
+        if !self.ids.insert(batch_id) {
+            return;
+        }
+        self.window.push_back((Instant::now(), batch_id));
+        while self.window.len() > self.max_batches {
+            self.forget_oldest();
+        }
+    }
+
+    /// It forgets every batch that completed longer than the retention ago
+    pub fn expire(&mut self) {
+        while let Some((completed_at, _)) = self.window.front() {
+            if completed_at.elapsed() < self.retention {
+                break;
+            }
+            self.forget_oldest();
+        }
+    }
+
+    fn forget_oldest(&mut self) {
+        if let Some((_, batch_id)) = self.window.pop_front() {
+            self.ids.remove(&batch_id);
+        }
+    }
+}
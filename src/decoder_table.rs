@@ -0,0 +1,380 @@
+use crate::error::Error;
+use crate::namespace::SenderNamespace;
+use crate::utils::decode_packet;
+use crate::BATCH_ID_SIZE;
+use raptorq::{Decoder, ObjectTransmissionInformation};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Bounds on the decoders a single decoder worker keeps for batches that have not decoded yet
+#[derive(Debug, Clone, Copy)]
+pub struct DecoderLimits {
+    /// Maximum number of batches decoded concurrently
+    pub max_batches: usize,
+    /// Maximum number of bytes of received symbols held by all decoders together
+    pub max_memory: usize,
+    /// Time after which a batch that received no packet is dropped
+    pub idle_timeout: Duration,
+}
+
+impl DecoderLimits {
+    /// It divides the limits evenly between the decoder workers, since every worker owns its own
+    /// decoder table
+    pub fn per_worker(&self, workers: usize) -> DecoderLimits {
+        let workers = workers.max(1);
+        DecoderLimits {
+            max_batches: self.max_batches.div_ceil(workers).max(1),
+            max_memory: self.max_memory / workers,
+            idle_timeout: self.idle_timeout,
+        }
+    }
+}
+
+/// Why an in-flight batch lost its decoder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// A new batch arrived while the table was full
+    TooManyBatches,
+    /// The symbols held by the decoders exceeded the memory limit
+    MemoryLimit,
+    /// No packet arrived for the batch within the idle timeout
+    Idle,
+}
+
+impl fmt::Display for EvictionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvictionReason::TooManyBatches => write!(f, "too many in-flight batches"),
+            EvictionReason::MemoryLimit => write!(f, "decoder memory limit"),
+            EvictionReason::Idle => write!(f, "idle timeout"),
+        }
+    }
+}
+
+/// An in-flight batch dropped from the table
+#[derive(Debug)]
+pub struct Eviction {
+    pub batch_id: [u8; BATCH_ID_SIZE],
+    pub reason: EvictionReason,
+    /// Number of unique symbols the decoder had received
+    pub num_packets: usize,
+    /// Time since the batch last received a packet
+    pub idle: Duration,
+}
+
+struct InflightBatch {
+    num_packets: usize,
+    /// Source block number and encoding symbol id of every symbol fed to the decoder
+    symbol_ids: HashSet<(u8, u32)>,
+    decoder: Decoder,
+    last_activity: Instant,
+}
+
+/// Decoders of the batches that have not decoded yet, bounded in number, memory and idle time.
+/// When a limit is hit the least recently active batch is evicted first.
+pub struct DecoderTable {
+    batches: HashMap<[u8; BATCH_ID_SIZE], InflightBatch>,
+    config: ObjectTransmissionInformation,
+    limits: DecoderLimits,
+    /// Bytes of symbols currently held by the decoders
+    memory: usize,
+}
+
+impl DecoderTable {
+    pub fn new(config: ObjectTransmissionInformation, limits: DecoderLimits) -> Self {
+        DecoderTable {
+            batches: HashMap::new(),
+            config,
+            limits,
+            memory: 0,
+        }
+    }
+
+    /// Whether no batch is in flight
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    /// It feeds a symbol to the decoder of its batch, creating the decoder on the first packet of
+    /// the batch and evicting other batches if that breaks one of the limits. A symbol the decoder
+    /// already has is not fed again, it cannot bring the batch any closer to decoding.
+    ///
+    /// Arguments:
+    ///
+    /// * `batch_id`: The batch id of the packet.
+    /// * `symbol_id`: Source block number and encoding symbol id of the packet.
+    /// * `payload`: The serialized RaptorQ packet, without the batch header.
+    /// * `evicted`: Batches evicted to make room are appended here.
+    ///
+    /// Returns:
+    ///
+    /// The number of unique symbols used and the decoded bytes once the batch decodes
+    pub fn decode(
+        &mut self,
+        batch_id: [u8; BATCH_ID_SIZE],
+        symbol_id: (u8, u32),
+        payload: &[u8],
+        evicted: &mut Vec<Eviction>,
+    ) -> Option<(usize, Vec<u8>)> {
+        if !self.batches.contains_key(&batch_id) {
+            while self.batches.len() >= self.limits.max_batches {
+                match self.evict_least_recent(&batch_id, EvictionReason::TooManyBatches) {
+                    Some(eviction) => evicted.push(eviction),
+                    None => break,
+                }
+            }
+        }
+
+        let config = self.config;
+        let batch = self
+            .batches
+            .entry(batch_id)
+            .or_insert_with(|| InflightBatch {
+                num_packets: 0,
+                symbol_ids: HashSet::new(),
+                decoder: Decoder::new(config),
+                last_activity: Instant::now(),
+            });
+        batch.last_activity = Instant::now();
+        if !batch.symbol_ids.insert(symbol_id) {
+            return None;
+        }
+        batch.num_packets += 1;
+        self.memory += self.config.symbol_size() as usize;
+
+        if let Some(result) = decode_packet(&mut batch.decoder, payload) {
+            let num_packets = batch.num_packets;
+            self.remove(&batch_id);
+            return Some((num_packets, result));
+        }
+
+        // The batch being fed is never evicted for memory, it would never get the chance to decode
+        while self.memory > self.limits.max_memory {
+            match self.evict_least_recent(&batch_id, EvictionReason::MemoryLimit) {
+                Some(eviction) => evicted.push(eviction),
+                None => break,
+            }
+        }
+        None
+    }
+
+    /// Number of distinct symbols received for an in-flight batch
+    pub fn unique_symbols(&self, batch_id: &[u8; BATCH_ID_SIZE]) -> usize {
+        self.batches
+            .get(batch_id)
+            .map_or(0, |batch| batch.num_packets)
+    }
+
+    /// It evicts every batch that has not received a packet within the idle timeout
+    pub fn evict_idle(&mut self) -> Vec<Eviction> {
+        let idle: Vec<[u8; BATCH_ID_SIZE]> = self
+            .batches
+            .iter()
+            .filter(|(_, batch)| batch.last_activity.elapsed() >= self.limits.idle_timeout)
+            .map(|(batch_id, _)| *batch_id)
+            .collect();
+        idle.into_iter()
+            .filter_map(|batch_id| self.evict(&batch_id, EvictionReason::Idle))
+            .collect()
+    }
+
+    fn evict_least_recent(
+        &mut self,
+        except: &[u8; BATCH_ID_SIZE],
+        reason: EvictionReason,
+    ) -> Option<Eviction> {
+        let batch_id = self
+            .batches
+            .iter()
+            .filter(|(batch_id, _)| *batch_id != except)
+            .min_by_key(|(_, batch)| batch.last_activity)
+            .map(|(batch_id, _)| *batch_id)?;
+        self.evict(&batch_id, reason)
+    }
+
+    fn evict(
+        &mut self,
+        batch_id: &[u8; BATCH_ID_SIZE],
+        reason: EvictionReason,
+    ) -> Option<Eviction> {
+        let batch = self.remove(batch_id)?;
+        Some(Eviction {
+            batch_id: *batch_id,
+            reason,
+            num_packets: batch.num_packets,
+            idle: batch.last_activity.elapsed(),
+        })
+    }
+
+    fn remove(&mut self, batch_id: &[u8; BATCH_ID_SIZE]) -> Option<InflightBatch> {
+        let batch = self.batches.remove(batch_id)?;
+        self.memory = self
+            .memory
+            .saturating_sub(batch.num_packets * self.config.symbol_size() as usize);
+        Some(batch)
+    }
+}
+
+/// A decoder table per sender, so that the batches of one sender are evicted before those of
+/// another. The limits cap the batches of all the senders together: past them, batches are evicted
+/// from the sender holding the most, so that a busy sender cannot crowd the others out. At most `max_senders` senders are decoded at once.
+pub struct SenderDecoders {
+    tables: HashMap<SenderNamespace, DecoderTable>,
+    config: ObjectTransmissionInformation,
+    limits: DecoderLimits,
+    max_senders: usize,
+}
+
+impl SenderDecoders {
+    pub fn new(
+        config: ObjectTransmissionInformation,
+        limits: DecoderLimits,
+        max_senders: usize,
+    ) -> Self {
+        SenderDecoders {
+            tables: HashMap::new(),
+            config,
+            limits,
+            max_senders: max_senders.max(1),
+        }
+    }
+
+    /// It feeds a symbol to the decoder table of the sender of its batch, see
+    /// [`DecoderTable::decode`]. The table is created on the first packet of the sender.
+    ///
+    /// Returns:
+    ///
+    /// An error when the batch belongs to a new sender while `max_senders` senders already have
+    /// batches in flight
+    pub fn decode(
+        &mut self,
+        batch_id: [u8; BATCH_ID_SIZE],
+        symbol_id: (u8, u32),
+        payload: &[u8],
+        evicted: &mut Vec<Eviction>,
+    ) -> Result<Option<(usize, Vec<u8>)>, Error> {
+        let namespace = SenderNamespace::of_batch(&batch_id);
+        if !self.tables.contains_key(&namespace) && self.tables.len() >= self.max_senders {
+            return Err(Error::Decode(format!(
+                "sender {} over the limit of {} senders",
+                namespace, self.max_senders
+            )));
+        }
+        let (config, limits) = (self.config, self.limits);
+        let table = self
+            .tables
+            .entry(namespace)
+            .or_insert_with(|| DecoderTable::new(config, limits));
+        let result = table.decode(batch_id, symbol_id, payload, evicted);
+        self.enforce_limits(&batch_id, evicted);
+        Ok(result)
+    }
+
+    /// It evicts batches until the tables of all the senders together are within the limits,
+    /// never the batch being fed, and forgets the senders left without any batch
+    fn enforce_limits(&mut self, except: &[u8; BATCH_ID_SIZE], evicted: &mut Vec<Eviction>) {
+        loop {
+            let batches: usize = self.tables.values().map(|table| table.batches.len()).sum();
+            let memory: usize = self.tables.values().map(|table| table.memory).sum();
+            let reason = if batches > self.limits.max_batches {
+                EvictionReason::TooManyBatches
+            } else if memory > self.limits.max_memory {
+                EvictionReason::MemoryLimit
+            } else {
+                break;
+            };
+            let largest = self
+                .tables
+                .values_mut()
+                .filter(|table| table.batches.keys().any(|batch_id| batch_id != except))
+                .max_by_key(|table| match reason {
+                    EvictionReason::TooManyBatches => table.batches.len(),
+                    _ => table.memory,
+                });
+            match largest.and_then(|table| table.evict_least_recent(except, reason)) {
+                Some(eviction) => evicted.push(eviction),
+                None => break,
+            }
+        }
+        self.tables.retain(|_, table| !table.is_empty());
+    }
+
+    /// Number of distinct symbols received for an in-flight batch
+    pub fn unique_symbols(&self, batch_id: &[u8; BATCH_ID_SIZE]) -> usize {
+        self.tables
+            .get(&SenderNamespace::of_batch(batch_id))
+            .map_or(0, |table| table.unique_symbols(batch_id))
+    }
+
+    /// It evicts the idle batches of every sender, forgetting the senders left without any batch
+    pub fn evict_idle(&mut self) -> Vec<Eviction> {
+        let evicted = self
+            .tables
+            .values_mut()
+            .flat_map(DecoderTable::evict_idle)
+            .collect();
+        self.tables.retain(|_, table| !table.is_empty());
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{encode_into_packets, source_symbol_count};
+    use rand::RngCore;
+    use raptorq::PayloadId;
+
+    #[test]
+    fn decodes_with_about_the_source_symbol_count() {
+        let mut payload = vec![0; 200_000];
+        rand::thread_rng().fill_bytes(&mut payload);
+        let packets = encode_into_packets(&payload, 32);
+        let config = packets.config;
+        let source_symbols = source_symbol_count(&config) as usize;
+
+        let limits = DecoderLimits {
+            max_batches: 1,
+            max_memory: usize::MAX,
+            idle_timeout: Duration::from_secs(60),
+        };
+        let mut decoders = SenderDecoders::new(config, limits, 1);
+        let batch_id = [b'a'; BATCH_ID_SIZE];
+        let mut evicted = vec![];
+        // Every 10th source symbol is lost and made up for by the repair symbols, and every symbol
+        // arrives twice
+        let received = packets
+            .source
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 10 != 0)
+            .map(|(_, packet)| packet)
+            .chain(packets.repair.iter());
+        let mut decoded = None;
+        for packet in received.flat_map(|packet| [packet, packet]) {
+            let payload_id = PayloadId::deserialize(packet[..4].try_into().unwrap());
+            let symbol_id = (
+                payload_id.source_block_number(),
+                payload_id.encoding_symbol_id(),
+            );
+            if let Some(result) = decoders
+                .decode(batch_id, symbol_id, packet, &mut evicted)
+                .unwrap()
+            {
+                decoded = Some(result);
+                break;
+            }
+        }
+
+        let (num_packets, contents) = decoded.expect("the batch did not decode");
+        assert_eq!(contents, payload);
+        assert!(evicted.is_empty());
+        assert!(
+            num_packets >= source_symbols && num_packets <= source_symbols + 2,
+            "decoded with {} symbols, {} source symbols",
+            num_packets,
+            source_symbols
+        );
+    }
+}
@@ -0,0 +1,664 @@
+use crate::auth::{ClusterKey, TAG_SIZE};
+use crate::error::Error;
+use crate::liveness::{Liveness, HEARTBEAT_KIND};
+use crate::utils::MTU_SIZE;
+use crate::{NodeAddress, NodeType};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+/// Leading byte of a peer records message, data packets start with 0
+pub const PEER_RECORDS_KIND: u8 = 2;
+
+/// Largest peer records message before its authentication tag, so that it stays within the MTU
+const MAX_RECORDS_SIZE: usize = MTU_SIZE - TAG_SIZE;
+
+/// Most nodes whose newest wallclock is remembered after they expired
+const MAX_EXPIRED_WALLCLOCKS: usize = 4096;
+
+/// Size of the kind, pull flag and number of records leading a peer records message
+const RECORDS_HEADER_SIZE: usize = 3;
+
+/// A node as advertised by itself, `wallclock` telling which of two records is the newest. The
+/// clocks of two nodes may disagree, so the wallclock only ever orders the records of a single
+/// node: how old a record is travels with it as an age, measured on the clock of every node that
+/// relays it.
+#[derive(Debug, Clone)]
+struct PeerRecord {
+    node: NodeAddress,
+    /// Milliseconds since the Unix epoch on the node when it last refreshed its record
+    wallclock: u64,
+}
+
+struct Peer {
+    record: PeerRecord,
+    /// When a newer record of the peer last arrived, `None` for entries that never expire
+    refreshed: Option<Instant>,
+}
+
+/// The nodes of the cluster known to be alive. It is either static, built from the node list, or
+/// kept up to date by gossiping signed peer records with the seeds and the known peers. With
+/// heartbeats, the receivers found dead are left out until they are heard from again.
+pub struct Membership {
+    peers: RwLock<HashMap<(String, u16), Peer>>,
+    /// Newest wallclock merged for every node, kept after the node expires so that a replayed
+    /// record cannot bring it back
+    wallclocks: Mutex<HashMap<(String, u16), u64>>,
+    /// The record of this node, refreshed on every gossip round
+    own: Option<NodeAddress>,
+    /// Addresses contacted to join the cluster
+    seeds: Vec<SocketAddr>,
+    key: ClusterKey,
+    ttl: Duration,
+    /// Bumped on every change of the live nodes
+    version: AtomicU64,
+    /// Which receivers answer heartbeats, if they are sent
+    liveness: Option<Liveness>,
+}
+
+impl Membership {
+    /// A membership that never changes, made of the given nodes
+    pub fn fixed(nodes: Vec<NodeAddress>, key: ClusterKey) -> Self {
+        let peers = nodes
+            .into_iter()
+            .map(|node| {
+                let record = PeerRecord { node, wallclock: 0 };
+                (
+                    peer_key(&record.node),
+                    Peer {
+                        record,
+                        refreshed: None,
+                    },
+                )
+            })
+            .collect();
+        Membership {
+            peers: RwLock::new(peers),
+            wallclocks: Mutex::new(HashMap::new()),
+            own: None,
+            seeds: vec![],
+            key,
+            ttl: Duration::MAX,
+            version: AtomicU64::new(0),
+            liveness: None,
+        }
+    }
+
+    /// A membership discovered through the seeds
+    ///
+    /// Arguments:
+    ///
+    /// * `own`: The record this node advertises.
+    /// * `seeds`: Addresses contacted to join the cluster.
+    /// * `key`: The cluster key signing the peer records.
+    /// * `ttl`: Time after which a peer whose record was not refreshed is dropped.
+    pub fn discovered(
+        own: NodeAddress,
+        seeds: Vec<SocketAddr>,
+        key: ClusterKey,
+        ttl: Duration,
+    ) -> Self {
+        let membership = Membership {
+            peers: RwLock::new(HashMap::new()),
+            wallclocks: Mutex::new(HashMap::new()),
+            own: Some(own.clone()),
+            seeds,
+            key,
+            ttl,
+            version: AtomicU64::new(0),
+            liveness: None,
+        };
+        membership.merge(
+            PeerRecord {
+                node: own,
+                wallclock: now_ms(),
+            },
+            Duration::ZERO,
+        );
+        membership
+    }
+
+    /// It leaves the receivers found dead by `liveness` out of the live nodes
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = Some(liveness);
+        self
+    }
+
+    /// Whether the nodes come from the node list, and so are the same on every node
+    pub fn is_fixed(&self) -> bool {
+        self.own.is_none()
+    }
+
+    pub fn liveness(&self) -> Option<&Liveness> {
+        self.liveness.as_ref()
+    }
+
+    /// The live nodes, ordered by address so that every node sees them in the same order
+    pub fn live_nodes(&self) -> Vec<NodeAddress> {
+        let nodes = self.known_nodes();
+        match &self.liveness {
+            Some(liveness) => nodes
+                .into_iter()
+                .filter(|node| liveness.is_alive(node))
+                .collect(),
+            None => nodes,
+        }
+    }
+
+    /// The nodes whose record has not expired, including those found dead by the heartbeats
+    pub fn known_nodes(&self) -> Vec<NodeAddress> {
+        let peers = self.peers.read().unwrap();
+        let mut keys: Vec<&(String, u16)> = peers.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|key| peers[key].record.node.clone())
+            .collect()
+    }
+
+    /// A counter that changes whenever the live nodes change
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    /// It handles a peer records message, merging the records it carries
+    ///
+    /// Returns:
+    ///
+    /// The messages to answer with if the sender asked for the records of this node
+    pub fn handle_message(&self, message: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+        let message = self
+            .key
+            .verify(message)
+            .ok_or_else(|| Error::MalformedPacket("peer records signature mismatch".to_string()))?;
+        let (pull, records) = parse_records(message)?;
+        for (record, age) in records {
+            self.merge(record, age);
+        }
+        Ok(if pull {
+            self.records_messages(false)
+        } else {
+            vec![]
+        })
+    }
+
+    /// It handles a heartbeat received from `from`
+    ///
+    /// Returns:
+    ///
+    /// The heartbeat to answer with if the sender asked for one
+    pub fn handle_heartbeat(
+        &self,
+        message: &[u8],
+        from: SocketAddr,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let Some(liveness) = &self.liveness else {
+            return Err(Error::MalformedPacket(
+                "heartbeat while heartbeats are disabled".to_string(),
+            ));
+        };
+        let (reply, back) = liveness.handle_message(message, from)?;
+        if back {
+            self.version.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(reply)
+    }
+
+    /// It marks the receivers not heard from within the heartbeat timeout as dead
+    pub fn check_liveness(&self) {
+        if let Some(liveness) = &self.liveness {
+            if liveness.check(&self.known_nodes()) {
+                self.version.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// It refreshes the record of this node and drops the peers not heard of within the ttl
+    pub fn refresh(&self) {
+        if let Some(own) = &self.own {
+            self.merge(
+                PeerRecord {
+                    node: own.clone(),
+                    wallclock: now_ms(),
+                },
+                Duration::ZERO,
+            );
+        }
+        let mut peers = self.peers.write().unwrap();
+        let before = peers.len();
+        peers.retain(|_, peer| match peer.refreshed {
+            Some(refreshed) if refreshed.elapsed() >= self.ttl => {
+                println!(
+                    "Expired {:?} node {}:{}: not heard of for {:?}",
+                    peer.record.node.role,
+                    peer.record.node.ip_addr,
+                    peer.record.node.port,
+                    refreshed.elapsed()
+                );
+                false
+            }
+            _ => true,
+        });
+        if peers.len() != before {
+            self.version.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// It sends the records of every live node to the seeds and to the live peers
+    ///
+    /// Arguments:
+    ///
+    /// * `socket`: The socket to gossip from, the one the node listens on.
+    /// * `pull`: Whether the peers should answer with their own records.
+    pub async fn gossip(&self, socket: &UdpSocket, pull: bool) -> Result<(), Error> {
+        let own_key = self.own.as_ref().map(peer_key);
+        let mut targets: Vec<SocketAddr> = self.seeds.clone();
+        for node in self.known_nodes() {
+            if Some(peer_key(&node)) == own_key {
+                continue;
+            }
+            if let Ok(address) = format!("{}:{}", node.ip_addr, node.port).parse() {
+                if !targets.contains(&address) {
+                    targets.push(address);
+                }
+            }
+        }
+        let mut result = Ok(());
+        for message in self.records_messages(pull) {
+            for target in &targets {
+                // One unreachable peer must not keep the records from the others
+                if let Err(e) = socket.send_to(&message, target).await {
+                    result = Err(Error::from(e));
+                }
+            }
+        }
+        result
+    }
+
+    /// It keeps a record unless a record of the node at least as new was merged before, even if
+    /// the node has expired since
+    ///
+    /// Arguments:
+    ///
+    /// * `record`: The record of a node.
+    /// * `age`: Time since the node refreshed the record, as relayed along with it.
+    fn merge(&self, record: PeerRecord, age: Duration) {
+        // A record older than the ttl is relayed from a peer that has not expired it yet, and
+        // would bring a dead node back
+        if age >= self.ttl {
+            return;
+        }
+        let refreshed = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+        let mut peers = self.peers.write().unwrap();
+        let key = peer_key(&record.node);
+        // A captured record replayed within the ttl carries a wallclock already merged, even once
+        // the node expired
+        let mut wallclocks = self.wallclocks.lock().unwrap();
+        if wallclocks
+            .get(&key)
+            .is_some_and(|newest| record.wallclock <= *newest)
+        {
+            return;
+        }
+        if peers.get(&key).is_some_and(|peer| peer.refreshed.is_none()) {
+            return;
+        }
+        wallclocks.insert(key.clone(), record.wallclock);
+        if wallclocks.len() > peers.len() + MAX_EXPIRED_WALLCLOCKS {
+            // The oldest wallclock of an expired node goes first, the live nodes keep theirs
+            let oldest = wallclocks
+                .iter()
+                .filter(|(node, _)| !peers.contains_key(*node) && **node != key)
+                .min_by_key(|(_, wallclock)| **wallclock)
+                .map(|(node, _)| node.clone());
+            if let Some(oldest) = oldest {
+                wallclocks.remove(&oldest);
+            }
+        }
+        drop(wallclocks);
+        match peers.get_mut(&key) {
+            Some(peer) => {
+                peer.record = record;
+                peer.refreshed = Some(refreshed);
+            }
+            None => {
+                println!(
+                    "Discovered {:?} node {}:{}",
+                    record.node.role, record.node.ip_addr, record.node.port
+                );
+                peers.insert(
+                    key,
+                    Peer {
+                        record,
+                        refreshed: Some(refreshed),
+                    },
+                );
+                self.version.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The signed messages carrying the records of every live node
+    fn records_messages(&self, pull: bool) -> Vec<Vec<u8>> {
+        let records: Vec<(PeerRecord, Duration)> = self
+            .peers
+            .read()
+            .unwrap()
+            .values()
+            .map(|peer| {
+                let age = peer.refreshed.map_or(Duration::ZERO, |r| r.elapsed());
+                (peer.record.clone(), age)
+            })
+            .collect();
+        let mut messages = vec![];
+        let mut chunk: Vec<Vec<u8>> = vec![];
+        let mut chunk_size = RECORDS_HEADER_SIZE;
+        for record in records
+            .iter()
+            .map(|(record, age)| serialize_record(record, *age))
+        {
+            if !chunk.is_empty()
+                && (chunk_size + record.len() > MAX_RECORDS_SIZE || chunk.len() == u8::MAX as usize)
+            {
+                messages.push(self.key.sign(records_message(pull, &chunk)));
+                chunk.clear();
+                chunk_size = RECORDS_HEADER_SIZE;
+            }
+            chunk_size += record.len();
+            chunk.push(record);
+        }
+        if !chunk.is_empty() || messages.is_empty() {
+            messages.push(self.key.sign(records_message(pull, &chunk)));
+        }
+        messages
+    }
+}
+
+/// It sends the peer records of this node every `interval`, drops the expired peers, and answers
+/// the peer records messages received by the receive loop
+pub async fn gossip_loop(membership: Arc<Membership>, socket: Arc<UdpSocket>, interval: Duration) {
+    // Asking for the records of the seeds is how a node joins the cluster
+    let mut pull = true;
+    loop {
+        membership.refresh();
+        if let Err(e) = membership.gossip(&socket, pull).await {
+            println!("Failed to gossip peer records: {}", e);
+        }
+        pull = membership.known_nodes().len() <= 1;
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// It answers the peer records messages and heartbeats arriving on `socket`, for nodes whose socket
+/// is not read by the receive loop
+pub async fn answer_loop(membership: Arc<Membership>, socket: Arc<UdpSocket>) {
+    let mut buf = [0; MTU_SIZE];
+    loop {
+        let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let replies = if len > 0 && buf[0] == HEARTBEAT_KIND {
+            membership
+                .handle_heartbeat(&buf[..len], from)
+                .map(|reply| reply.into_iter().collect())
+        } else {
+            membership.handle_message(&buf[..len])
+        };
+        match replies {
+            Ok(replies) => {
+                for reply in replies {
+                    let _ = socket.send_to(&reply, from).await;
+                }
+            }
+            Err(e) => println!("Ignoring datagram from {}: {}", from, e),
+        }
+    }
+}
+
+fn peer_key(node: &NodeAddress) -> (String, u16) {
+    (node.ip_addr.clone(), node.port)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn role_to_byte(role: &NodeType) -> u8 {
+    match role {
+        NodeType::Sender => 0,
+        NodeType::Receiver => 1,
+        NodeType::Relay => 2,
+        NodeType::Observer => 3,
+    }
+}
+
+fn role_from_byte(role: u8) -> Result<NodeType, Error> {
+    match role {
+        0 => Ok(NodeType::Sender),
+        1 => Ok(NodeType::Receiver),
+        2 => Ok(NodeType::Relay),
+        3 => Ok(NodeType::Observer),
+        _ => Err(Error::MalformedPacket(format!(
+            "unknown node role {}",
+            role
+        ))),
+    }
+}
+
+/// Layout: kind, pull flag, number of records, then the records made by `serialize_record`
+fn records_message(pull: bool, records: &[Vec<u8>]) -> Vec<u8> {
+    let mut message = vec![PEER_RECORDS_KIND, pull as u8, records.len() as u8];
+    for record in records {
+        message.extend_from_slice(record);
+    }
+    message
+}
+
+/// Layout: the length of the ip and the ip, the port, the role, the weight, the wallclock, the age
+/// in milliseconds, the length of the id and the id, then the number of other addresses and every
+/// address with its length. An empty id stands for no id.
+fn serialize_record(record: &PeerRecord, age: Duration) -> Vec<u8> {
+    let mut message = vec![];
+    push_string(&mut message, &record.node.ip_addr);
+    message.extend_from_slice(&record.node.port.to_be_bytes());
+    message.push(role_to_byte(&record.node.role));
+    message.extend_from_slice(&record.node.weight.to_be_bytes());
+    message.extend_from_slice(&record.wallclock.to_be_bytes());
+    message.extend_from_slice(&(age.as_millis() as u64).to_be_bytes());
+    push_string(&mut message, record.node.id.as_deref().unwrap_or_default());
+    message.push(record.node.addresses.len() as u8);
+    for address in &record.node.addresses {
+        push_string(&mut message, address);
+    }
+    message
+}
+
+/// It appends a string preceded by its length, the node options keep it under 256 bytes
+fn push_string(message: &mut Vec<u8>, string: &str) {
+    message.push(string.len() as u8);
+    message.extend_from_slice(string.as_bytes());
+}
+
+fn parse_records(message: &[u8]) -> Result<(bool, Vec<(PeerRecord, Duration)>), Error> {
+    let mut reader = Reader(message);
+    if reader.take(1)?[0] != PEER_RECORDS_KIND {
+        return Err(Error::MalformedPacket(
+            "not a peer records message".to_string(),
+        ));
+    }
+    let pull = reader.take(1)?[0] != 0;
+    let count = reader.take(1)?[0];
+    let mut records = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let ip_addr = reader.string()?;
+        let port = u16::from_be_bytes(reader.array()?);
+        let role = role_from_byte(reader.take(1)?[0])?;
+        let weight = u64::from_be_bytes(reader.array()?);
+        let wallclock = u64::from_be_bytes(reader.array()?);
+        let age = Duration::from_millis(u64::from_be_bytes(reader.array()?));
+        let id = Some(reader.string()?).filter(|id| !id.is_empty());
+        let address_count = reader.take(1)?[0];
+        let addresses = (0..address_count)
+            .map(|_| reader.string())
+            .collect::<Result<Vec<String>, Error>>()?;
+        records.push((
+            PeerRecord {
+                node: NodeAddress {
+                    ip_addr,
+                    port,
+                    role,
+                    weight,
+                    id,
+                    addresses,
+                },
+                wallclock,
+            },
+            age,
+        ));
+    }
+    Ok((pull, records))
+}
+
+/// It reads the fields of a message, failing instead of panicking when the message is too short
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::MalformedPacket("truncated peer records".to_string()));
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    /// A string preceded by its length
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.take(1)?[0] as usize;
+        std::str::from_utf8(self.take(len)?)
+            .map(str::to_string)
+            .map_err(|_| Error::MalformedPacket("peer record field is not UTF-8".to_string()))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(port: u16) -> NodeAddress {
+        NodeAddress {
+            ip_addr: "127.0.0.1".to_string(),
+            port,
+            role: NodeType::Receiver,
+            weight: 1,
+            id: None,
+            addresses: vec![],
+        }
+    }
+
+    fn record(port: u16, wallclock: u64) -> PeerRecord {
+        PeerRecord {
+            node: node(port),
+            wallclock,
+        }
+    }
+
+    #[test]
+    fn replayed_record_does_not_bring_an_expired_node_back() {
+        let ttl = Duration::from_millis(50);
+        let membership = Membership::discovered(node(1235), vec![], ClusterKey::new("test"), ttl);
+        membership.merge(record(1236, 1000), Duration::ZERO);
+        assert_eq!(membership.known_nodes().len(), 2);
+        std::thread::sleep(ttl);
+        membership.refresh();
+        assert_eq!(membership.known_nodes().len(), 1);
+
+        membership.merge(record(1236, 1000), Duration::ZERO);
+        assert_eq!(membership.known_nodes().len(), 1);
+        membership.merge(record(1236, 1001), Duration::ZERO);
+        assert_eq!(membership.known_nodes().len(), 2);
+    }
+
+    /// A signed message carrying the record of the node listening on 1236
+    fn records(key: &ClusterKey) -> Vec<u8> {
+        key.sign(records_message(
+            false,
+            &[serialize_record(&record(1236, 1000), Duration::ZERO)],
+        ))
+    }
+
+    #[test]
+    fn parse_records_rejects_truncated_messages() {
+        let message = records_message(
+            true,
+            &[serialize_record(
+                &record(1236, 1000),
+                Duration::from_millis(7),
+            )],
+        );
+        let (pull, records) = parse_records(&message).unwrap();
+        assert!(pull);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0.node, node(1236));
+        assert_eq!(records[0].1, Duration::from_millis(7));
+
+        for len in 0..message.len() {
+            assert!(matches!(
+                parse_records(&message[..len]),
+                Err(Error::MalformedPacket(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_records_rejects_bad_lengths() {
+        let message = records_message(
+            false,
+            &[serialize_record(&record(1236, 1000), Duration::ZERO)],
+        );
+
+        // One more record than the message carries
+        let mut count = message.clone();
+        count[2] = 2;
+        assert!(parse_records(&count).is_err());
+
+        // An ip running past the end of the message
+        let mut ip = message;
+        ip[RECORDS_HEADER_SIZE] = u8::MAX;
+        assert!(parse_records(&ip).is_err());
+    }
+
+    #[test]
+    fn handle_message_rejects_tampered_and_truncated_messages() {
+        let key = ClusterKey::new("test");
+        let membership = Membership::discovered(node(1235), vec![], key.clone(), Duration::MAX);
+
+        let mut tampered = records(&key);
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(membership.handle_message(&tampered).is_err());
+        assert!(membership
+            .handle_message(&tampered[..TAG_SIZE - 1])
+            .is_err());
+
+        // Signed with the cluster key, but cut short before signing
+        let message = records(&key);
+        let truncated = key.sign(message[..message.len() - TAG_SIZE - 1].to_vec());
+        assert!(membership.handle_message(&truncated).is_err());
+        assert_eq!(membership.known_nodes().len(), 1);
+
+        membership.handle_message(&records(&key)).unwrap();
+        assert_eq!(membership.known_nodes().len(), 2);
+    }
+}
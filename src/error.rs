@@ -0,0 +1,40 @@
+use std::{fmt, io};
+
+/// Everything that can go wrong while sending or receiving batches
+#[derive(Debug)]
+pub enum Error {
+    /// A socket, file or pipe operation failed
+    Io(io::Error),
+    /// A datagram that does not follow the packet layout
+    MalformedPacket(String),
+    /// A well formed packet that the decoder of its batch cannot use
+    Decode(String),
+    /// Missing or inconsistent command line options
+    Config(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::MalformedPacket(reason) => write!(f, "malformed packet: {}", reason),
+            Error::Decode(reason) => write!(f, "decode error: {}", reason),
+            Error::Config(reason) => write!(f, "configuration error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,118 @@
+use crate::auth::{ClusterKey, TAG_SIZE};
+use crate::error::Error;
+use crate::{NodeAddress, BATCH_ID_SIZE};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Leading byte of a have batch notice, data packets start with 0
+pub const HAVE_KIND: u8 = 3;
+
+/// Size of a signed notice: kind, batch id, receiver port and the authentication tag
+const HAVE_SIZE: usize = 1 + BATCH_ID_SIZE + 2 + TAG_SIZE;
+
+/// Sent by a receiver to its peers once it has decoded a batch, so that they stop forwarding the
+/// packets of the batch to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HaveNotice {
+    pub batch_id: [u8; BATCH_ID_SIZE],
+    /// Port the receiver listens on, identifying it along with the source address of the notice
+    pub receiver_port: u16,
+}
+
+impl HaveNotice {
+    /// It serializes and signs the notice
+    pub fn to_bytes(&self, key: &ClusterKey) -> Vec<u8> {
+        let mut message = Vec::with_capacity(HAVE_SIZE);
+        message.push(HAVE_KIND);
+        message.extend_from_slice(&self.batch_id);
+        message.extend_from_slice(&self.receiver_port.to_be_bytes());
+        key.sign(message)
+    }
+
+    /// It verifies and parses a signed notice
+    pub fn from_bytes(bytes: &[u8], key: &ClusterKey) -> Result<HaveNotice, Error> {
+        if bytes.len() != HAVE_SIZE || bytes[0] != HAVE_KIND {
+            return Err(Error::MalformedPacket(format!(
+                "have notice of {} bytes, expected {}",
+                bytes.len(),
+                HAVE_SIZE
+            )));
+        }
+        let message = key
+            .verify(bytes)
+            .ok_or_else(|| Error::MalformedPacket("have notice signature mismatch".to_string()))?;
+
+        let (batch_id, receiver_port) = message[1..].split_at(BATCH_ID_SIZE);
+        let mut id = [0; BATCH_ID_SIZE];
+        id.copy_from_slice(batch_id);
+        Ok(HaveNotice {
+            batch_id: id,
+            receiver_port: u16::from_be_bytes([receiver_port[0], receiver_port[1]]),
+        })
+    }
+}
+
+/// The peers known to have decoded every recent batch, filled by the receive loop and read by the
+/// forwarder. Batches are forgotten like the completed batches, once older than the retention or
+/// when more than `max_batches` are held.
+pub struct PeerHaves {
+    inner: Mutex<Haves>,
+    key: ClusterKey,
+    retention: Duration,
+    max_batches: usize,
+}
+
+struct Haves {
+    /// Address and listening port of the peers that announced every batch
+    peers: HashMap<[u8; BATCH_ID_SIZE], Vec<(IpAddr, u16)>>,
+    /// Order in which the batches were first announced, oldest first
+    window: VecDeque<(Instant, [u8; BATCH_ID_SIZE])>,
+}
+
+impl PeerHaves {
+    pub fn new(key: ClusterKey, retention: Duration, max_batches: usize) -> Self {
+        PeerHaves {
+            inner: Mutex::new(Haves {
+                peers: HashMap::new(),
+                window: VecDeque::new(),
+            }),
+            key,
+            retention,
+            max_batches: max_batches.max(1),
+        }
+    }
+
+    /// It records the notice received from `from`
+    pub fn handle_message(&self, message: &[u8], from: SocketAddr) -> Result<(), Error> {
+        let notice = HaveNotice::from_bytes(message, &self.key)?;
+        let mut haves = self.inner.lock().unwrap();
+        while let Some((announced_at, _)) = haves.window.front() {
+            if announced_at.elapsed() < self.retention && haves.window.len() < self.max_batches {
+                break;
+            }
+            if let Some((_, batch_id)) = haves.window.pop_front() {
+                haves.peers.remove(&batch_id);
+            }
+        }
+        if !haves.peers.contains_key(&notice.batch_id) {
+            haves.window.push_back((Instant::now(), notice.batch_id));
+        }
+        let peers = haves.peers.entry(notice.batch_id).or_default();
+        if !peers.contains(&(from.ip(), notice.receiver_port)) {
+            peers.push((from.ip(), notice.receiver_port));
+        }
+        Ok(())
+    }
+
+    /// Whether `node` announced that it decoded the batch
+    pub fn has(&self, batch_id: &[u8; BATCH_ID_SIZE], node: &NodeAddress) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .peers
+            .get(batch_id)
+            .is_some_and(|peers| peers.iter().any(|(ip, port)| node.is_at(*ip, *port)))
+    }
+}
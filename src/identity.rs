@@ -0,0 +1,68 @@
+use crate::NodeAddress;
+use nix::ifaddrs::getifaddrs;
+use std::net::{IpAddr, SocketAddrV4, SocketAddrV6};
+
+/// How a receiver recognizes itself in the node list, so that it never forwards to itself
+#[derive(Debug, Clone)]
+pub struct SelfIdentity {
+    /// Configured id of the node, which alone decides when set
+    node_id: Option<String>,
+    /// Port the node listens on
+    port: u16,
+    /// Addresses the node is bound to, every local interface address when bound to all of them
+    addresses: Vec<IpAddr>,
+}
+
+impl SelfIdentity {
+    /// Arguments:
+    ///
+    /// * `node_id`: The id of the node in the node list, if configured.
+    /// * `bind_ip`: The address the listening socket is bound to.
+    /// * `port`: The port the listening socket is bound to.
+    pub fn new(node_id: Option<String>, bind_ip: IpAddr, port: u16) -> Self {
+        let addresses = if bind_ip.is_unspecified() {
+            local_addresses()
+        } else {
+            vec![bind_ip]
+        };
+        SelfIdentity {
+            node_id,
+            port,
+            addresses,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Whether `node` is this node: the node with the same id if one is configured, otherwise the
+    /// node listening on the same port at one of the addresses this node is bound to
+    pub fn is(&self, node: &NodeAddress) -> bool {
+        if let Some(node_id) = &self.node_id {
+            return node.id.as_ref() == Some(node_id);
+        }
+        self.addresses
+            .iter()
+            .any(|address| node.is_at(*address, self.port))
+    }
+}
+
+/// The addresses of every local network interface, empty if they cannot be listed
+fn local_addresses() -> Vec<IpAddr> {
+    let Ok(interfaces) = getifaddrs() else {
+        return vec![];
+    };
+    interfaces
+        .filter_map(|interface| {
+            let address = interface.address?;
+            if let Some(v4) = address.as_sockaddr_in() {
+                Some(IpAddr::V4(*SocketAddrV4::from(*v4).ip()))
+            } else {
+                address
+                    .as_sockaddr_in6()
+                    .map(|v6| IpAddr::V6(*SocketAddrV6::from(*v6).ip()))
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,294 @@
+use crate::auth::{ClusterKey, TAG_SIZE};
+use crate::discovery::Membership;
+use crate::error::Error;
+use crate::identity::SelfIdentity;
+use crate::{NodeAddress, NodeType};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+/// Leading byte of a heartbeat, data packets start with 0
+pub const HEARTBEAT_KIND: u8 = 4;
+
+/// Size of a signed heartbeat: kind, reply flag, listening port, stamp and the authentication tag
+const HEARTBEAT_SIZE: usize = 1 + 1 + 2 + 8 + TAG_SIZE;
+
+/// Sent periodically by every node to the receivers, telling them it is alive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// Whether the receiver should answer with its own heartbeat, for nodes that receivers do not
+    /// send heartbeats to, such as the senders
+    pub reply: bool,
+    /// Port the node listens on, identifying it along with the source address of the heartbeat
+    pub port: u16,
+    /// Increases with every heartbeat of the node, so that a recorded heartbeat cannot be replayed.
+    /// It starts from the wallclock of the node, which keeps it increasing across restarts, but is
+    /// never compared with the clock of another node.
+    pub stamp: u64,
+}
+
+impl Heartbeat {
+    /// It serializes and signs the heartbeat
+    pub fn to_bytes(&self, key: &ClusterKey) -> Vec<u8> {
+        let mut message = Vec::with_capacity(HEARTBEAT_SIZE);
+        message.push(HEARTBEAT_KIND);
+        message.push(self.reply as u8);
+        message.extend_from_slice(&self.port.to_be_bytes());
+        message.extend_from_slice(&self.stamp.to_be_bytes());
+        key.sign(message)
+    }
+
+    /// It verifies and parses a signed heartbeat
+    pub fn from_bytes(bytes: &[u8], key: &ClusterKey) -> Result<Heartbeat, Error> {
+        if bytes.len() != HEARTBEAT_SIZE || bytes[0] != HEARTBEAT_KIND {
+            return Err(Error::MalformedPacket(format!(
+                "heartbeat of {} bytes, expected {}",
+                bytes.len(),
+                HEARTBEAT_SIZE
+            )));
+        }
+        let message = key
+            .verify(bytes)
+            .ok_or_else(|| Error::MalformedPacket("heartbeat signature mismatch".to_string()))?;
+        let mut stamp = [0; 8];
+        stamp.copy_from_slice(&message[4..12]);
+        Ok(Heartbeat {
+            reply: message[1] != 0,
+            port: u16::from_be_bytes([message[2], message[3]]),
+            stamp: u64::from_be_bytes(stamp),
+        })
+    }
+}
+
+/// Which receivers are alive, from the heartbeats heard from them. A receiver is dead once it has
+/// not been heard from within the timeout, and alive again with its next heartbeat. Receivers that
+/// were never heard from are given the timeout from the start of the node to show up. Relays and
+/// observers are tracked like receivers, this node and the senders are always alive. A heartbeat
+/// whose stamp is not above the last one heard from its node is a replay and is rejected.
+pub struct Liveness {
+    identity: SelfIdentity,
+    key: ClusterKey,
+    timeout: Duration,
+    started: Instant,
+    /// Stamp of the last heartbeat this node sent
+    stamp: AtomicU64,
+    /// When every node was last heard from and the stamp of its last heartbeat, by address and
+    /// listening port
+    heard: Mutex<HashMap<(IpAddr, u16), (Instant, u64)>>,
+    /// Receivers found dead by the last check and not heard from since
+    dead: Mutex<Vec<NodeAddress>>,
+}
+
+impl Liveness {
+    /// Arguments:
+    ///
+    /// * `identity`: How this node recognizes itself among the nodes.
+    /// * `key`: The cluster key signing the heartbeats.
+    /// * `timeout`: Time without a heartbeat after which a receiver is dead.
+    pub fn new(identity: SelfIdentity, key: ClusterKey, timeout: Duration) -> Self {
+        Liveness {
+            identity,
+            key,
+            timeout,
+            started: Instant::now(),
+            stamp: AtomicU64::new(0),
+            heard: Mutex::new(HashMap::new()),
+            dead: Mutex::new(vec![]),
+        }
+    }
+
+    /// Whether `node` is alive as of the last check or heartbeat
+    pub fn is_alive(&self, node: &NodeAddress) -> bool {
+        !self
+            .dead
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|dead| dead.ip_addr == node.ip_addr && dead.port == node.port)
+    }
+
+    /// Whether `node` has its liveness tracked
+    fn is_tracked(&self, node: &NodeAddress) -> bool {
+        node.role != NodeType::Sender && !self.identity.is(node)
+    }
+
+    /// Whether every tracked node among `nodes` has been heard from
+    pub fn all_heard(&self, nodes: &[NodeAddress]) -> bool {
+        let heard = self.heard.lock().unwrap();
+        nodes
+            .iter()
+            .filter(|node| self.is_tracked(node))
+            .all(|node| heard.keys().any(|(ip, port)| node.is_at(*ip, *port)))
+    }
+
+    /// The heartbeat this node sends, asking for a reply or not, with a stamp above the previous
+    /// ones
+    pub fn heartbeat(&self, reply: bool) -> Vec<u8> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut stamp = now;
+        let _ = self
+            .stamp
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                stamp = now.max(last + 1);
+                Some(stamp)
+            });
+        Heartbeat {
+            reply,
+            port: self.identity.port(),
+            stamp,
+        }
+        .to_bytes(&self.key)
+    }
+
+    /// It records the heartbeat received from `from`
+    ///
+    /// Returns:
+    ///
+    /// The heartbeat to answer with if `from` asked for one, and whether a dead node came back
+    pub fn handle_message(
+        &self,
+        message: &[u8],
+        from: SocketAddr,
+    ) -> Result<(Option<Vec<u8>>, bool), Error> {
+        let heartbeat = Heartbeat::from_bytes(message, &self.key)?;
+        {
+            let mut heard = self.heard.lock().unwrap();
+            let node = (from.ip(), heartbeat.port);
+            if let Some((_, last)) = heard.get(&node) {
+                if heartbeat.stamp <= *last {
+                    return Err(Error::MalformedPacket(format!(
+                        "replayed heartbeat, stamp {} after {}",
+                        heartbeat.stamp, last
+                    )));
+                }
+            }
+            heard.insert(node, (Instant::now(), heartbeat.stamp));
+        }
+        let mut dead = self.dead.lock().unwrap();
+        let before = dead.len();
+        dead.retain(|node| {
+            let back = node.is_at(from.ip(), heartbeat.port);
+            if back {
+                println!("Node {}:{} is back", node.ip_addr, node.port);
+            }
+            !back
+        });
+        let reply = heartbeat.reply.then(|| self.heartbeat(false));
+        Ok((reply, dead.len() != before))
+    }
+
+    /// It marks the tracked nodes among `nodes` not heard from within the timeout as dead
+    ///
+    /// Returns:
+    ///
+    /// Whether a node died
+    pub fn check(&self, nodes: &[NodeAddress]) -> bool {
+        let heard = self.heard.lock().unwrap();
+        let mut dead = self.dead.lock().unwrap();
+        let before = dead.len();
+        for node in nodes.iter().filter(|node| self.is_tracked(node)) {
+            let silent_for = heard
+                .iter()
+                .filter(|((ip, port), _)| node.is_at(*ip, *port))
+                .map(|(_, (heard_at, _))| heard_at.elapsed())
+                .min()
+                .unwrap_or_else(|| self.started.elapsed());
+            if silent_for >= self.timeout
+                && !dead
+                    .iter()
+                    .any(|dead| dead.ip_addr == node.ip_addr && dead.port == node.port)
+            {
+                println!(
+                    "Node {}:{} is down: no heartbeat for {:?}",
+                    node.ip_addr, node.port, silent_for
+                );
+                dead.push(node.clone());
+            }
+        }
+        dead.len() != before
+    }
+}
+
+/// It sends a heartbeat to every known receiver but this node every `interval` and checks which
+/// ones are still alive. The heartbeats of the receivers arrive through the receive loop, or the
+/// answer loop for the senders.
+///
+/// Arguments:
+///
+/// * `membership`: The nodes, whose liveness is tracked.
+/// * `socket`: The socket to send from, the one heartbeats are received on.
+/// * `interval`: Time between two heartbeats.
+/// * `reply`: Whether the receivers should answer, for nodes they do not send heartbeats to.
+pub async fn heartbeat_loop(
+    membership: Arc<Membership>,
+    socket: Arc<UdpSocket>,
+    interval: Duration,
+    reply: bool,
+) {
+    let Some(liveness) = membership.liveness() else {
+        return;
+    };
+    loop {
+        membership.check_liveness();
+        let heartbeat = liveness.heartbeat(reply);
+        for node in membership.known_nodes() {
+            if !liveness.is_tracked(&node) {
+                continue;
+            }
+            // One unreachable node must not keep the heartbeats from the others
+            if let Err(e) = socket
+                .send_to(&heartbeat, (&node.ip_addr[..], node.port))
+                .await
+            {
+                println!(
+                    "Failed to send a heartbeat to {}:{}: {}",
+                    node.ip_addr, node.port, e
+                );
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat() -> Heartbeat {
+        Heartbeat {
+            reply: true,
+            port: 1235,
+            stamp: 42,
+        }
+    }
+
+    #[test]
+    fn heartbeat_round_trips() {
+        let key = ClusterKey::new("test");
+        let bytes = heartbeat().to_bytes(&key);
+        assert_eq!(Heartbeat::from_bytes(&bytes, &key).unwrap(), heartbeat());
+    }
+
+    #[test]
+    fn heartbeat_with_a_tampered_tag_or_stamp_is_rejected() {
+        let key = ClusterKey::new("test");
+        let bytes = heartbeat().to_bytes(&key);
+
+        let mut tag = bytes.clone();
+        tag[HEARTBEAT_SIZE - 1] ^= 1;
+        assert!(Heartbeat::from_bytes(&tag, &key).is_err());
+
+        let mut stamp = bytes.clone();
+        stamp[11] ^= 1;
+        assert!(Heartbeat::from_bytes(&stamp, &key).is_err());
+
+        assert!(Heartbeat::from_bytes(&bytes, &ClusterKey::new("other")).is_err());
+        assert!(Heartbeat::from_bytes(&bytes[..HEARTBEAT_SIZE - 1], &key).is_err());
+    }
+}
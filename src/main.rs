@@ -1,28 +1,72 @@
+mod ack;
+mod auth;
+mod bench;
+mod completed_batches;
+mod decoder_table;
+mod discovery;
+mod error;
+mod have;
+mod identity;
+mod liveness;
+mod namespace;
+mod pipeline;
+mod progress;
+mod rate_limit;
+mod reencode;
+mod retransmit;
+mod sink;
+mod stats;
 mod utils;
-use std::collections::{HashMap, HashSet};
-use std::{io, str, sync::Arc, thread};
+use std::future::Future;
+use std::os::fd::AsFd;
+use std::sync::Mutex;
+use std::{fs, io, str, sync::Arc, thread};
 
-use anyhow::Result;
-use clap::Parser;
-use crossbeam_channel::{unbounded, Receiver};
+use clap::{ArgEnum, Parser, Subcommand};
 //use futures::executor::ThreadPool;
-use crate::utils::{file_writer, generate_46b_batch_id, read_file, reassemble_packets, MTU_SIZE};
-use futures::future::try_join_all;
+use crate::ack::{collect_acks, AckSender};
+use crate::auth::ClusterKey;
+use crate::bench::{run_bench, BenchArguments};
+use crate::completed_batches::CompletedBatches;
+use crate::decoder_table::{DecoderLimits, SenderDecoders};
+use crate::discovery::{answer_loop, gossip_loop, Membership, PEER_RECORDS_KIND};
+use crate::error::Error;
+use crate::have::{HaveNotice, PeerHaves, HAVE_KIND};
+use crate::identity::SelfIdentity;
+use crate::liveness::{heartbeat_loop, Liveness, HEARTBEAT_KIND};
+use crate::namespace::SenderNamespace;
+use crate::pipeline::{BackpressurePolicy, PipelineMonitor, StageReceiver};
+use crate::progress::{log_progress, ProgressTracker};
+use crate::rate_limit::{Admission, PeerRateLimiter, RateLimit, RatePolicy, RateUnit};
+use crate::reencode::{reencode_handler, NODES_WITH_REPAIR_RANGE};
+use crate::retransmit::{Forward, RetransmitConfig, RetransmitMode, ShardAssignment, Topology};
+use crate::sink::{DirectorySink, OutputKind, OutputSink, StreamSink};
+use crate::stats::{ReceiverStats, RunSummary};
+use crate::utils::{
+    batch_name, decoder_config, decoder_worker_index, file_writer, get_batch_id,
+    interleave_packets, read_file, reassemble_packets, relay_packets, MTU_SIZE,
+};
+use futures::future::join_all;
 use futures::prelude::*;
-use raptorq::Decoder;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::borrow::{Borrow, BorrowMut};
-use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::path::PathBuf;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::UNIX_EPOCH;
+use std::time::{Duration, Instant, SystemTime};
 use threadpool::ThreadPool;
 use tokio::net::UdpSocket;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
 use futures::stream::FuturesUnordered;
 
-use crate::utils::split_into_packets;
+use crate::utils::{encode_into_packets, split_into_packets};
 
 // using random 46 bytes - lets say ipfs Hash
 const BATCH_ID_SIZE: usize = 46;
@@ -30,20 +74,43 @@ const BATCH_ID_SIZE: usize = 46;
 //How many packets to recieve from socket in single system call
 pub const NUM_RCVMMSGS: usize = 32;
 
+/// Decoded batches waiting to be re-encoded, each holding a whole payload
+const REENCODE_QUEUE: usize = 4;
+
+/// How often the sender looks for the answers to its first heartbeats
+const HEARTBEAT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Cluster key used without `--cluster-key`, only fit for local tests
+const DEFAULT_CLUSTER_KEY: &str = "raptorq-simulation";
+
+/// It sends the packets of one or more batches to the receivers, then hands the socket they were
+/// sent from to a thread of its own that waits up to `ack_timeout` for the receivers to acknowledge
+/// them, so that the next batches are sent meanwhile
+///
+/// Returns:
+///
+/// The thread waiting for the acknowledgements, if `ack_timeout` is not zero
 #[tokio::main]
 async fn broadcast_to_peers(
-    batch_id: [u8; 46],
-    receivers: Vec<NodeAddress>,
+    batch_ids: Vec<[u8; BATCH_ID_SIZE]>,
+    topology: Arc<Topology>,
     packet_list: Vec<Vec<u8>>,
     num_packet_blast: usize,
-) -> Result<()> {
-    let udp_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await.unwrap());
+    key: ClusterKey,
+    ack_timeout: Duration,
+) -> Result<Option<thread::JoinHandle<()>>, Error> {
+    let udp_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
     let mut futs = FuturesUnordered::new();
     println!("Packet list {}", packet_list.len());
     let now = SystemTime::now();
+    let started = Instant::now();
+    let mut shares: BTreeMap<String, usize> = BTreeMap::new();
     for (packet_index, packet) in packet_list.iter().enumerate() {
         // Sharding/Distribution of packets as per no of nodes
-        let address = receivers[packet_index % receivers.len()].clone();
+        let address = topology.destination(packet_index, packet).clone();
+        *shares
+            .entry(format!("{}:{}", address.ip_addr, address.port))
+            .or_default() += 1;
         let packet = packet.clone();
         let sock = udp_socket.clone();
 
@@ -61,12 +128,54 @@ async fn broadcast_to_peers(
 
     while (futs.next().await).is_some() {}
 
+    let batch_names: Vec<&str> = batch_ids.iter().map(batch_name).collect();
     println!(
         "Batch: {}: Packets sent to recipients: {:?}",
-        str::from_utf8(&batch_id).unwrap(),
-        SystemTime::now().duration_since(now).unwrap()
+        batch_names.join(","),
+        SystemTime::now().duration_since(now).unwrap_or_default()
+    );
+    let share_list: Vec<String> = shares
+        .iter()
+        .map(|(node, packets)| format!("{} {}", node, packets))
+        .collect();
+    println!(
+        "Batch: {}: Packets per receiver: {}",
+        batch_names.join(","),
+        share_list.join(": ")
     );
 
+    if ack_timeout.is_zero() {
+        return Ok(None);
+    }
+    // Receivers only acknowledge to the address the packets came from, so the receivers handed
+    // no packet directly, such as those of weight 0, never acknowledge
+    let receivers: Vec<NodeAddress> = topology
+        .nodes()
+        .iter()
+        .filter(|node| shares.contains_key(&format!("{}:{}", node.ip_addr, node.port)))
+        .cloned()
+        .collect();
+    // The socket is registered with the runtime of this thread, the ack thread gets its own handle
+    let socket = std::net::UdpSocket::from(udp_socket.as_fd().try_clone_to_owned()?);
+    Ok(Some(thread::spawn(move || {
+        if let Err(e) = wait_for_acks(socket, batch_ids, receivers, key, started, ack_timeout) {
+            println!("Failed to wait for acks: {}", e);
+        }
+    })))
+}
+
+/// It waits for the receivers to acknowledge the batches sent from `socket`
+#[tokio::main(flavor = "current_thread")]
+async fn wait_for_acks(
+    socket: std::net::UdpSocket,
+    batch_ids: Vec<[u8; BATCH_ID_SIZE]>,
+    receivers: Vec<NodeAddress>,
+    key: ClusterKey,
+    started: Instant,
+    ack_timeout: Duration,
+) -> Result<(), Error> {
+    let socket = UdpSocket::from_std(socket)?;
+    collect_acks(&socket, &batch_ids, &receivers, &key, started, ack_timeout).await;
     Ok(())
 }
 
@@ -77,205 +186,833 @@ async fn broadcast_to_peers(
 ///
 /// * `filename`: Path to the file to be sent
 /// * `number_of_chunks`: The number of packets to be sent to the receiver nodes.
-/// * `receivers`: A list of nodes to send the file to.
+/// * `membership`: The live nodes, read again for every batch.
+/// * `namespace`: The prefix of the batch ids of this sender.
+/// * `retransmit`: How the packets are spread over the receivers.
 /// * `num_batches`: Number of batches to send
 /// * `batch_parallelism`: The number of batches to send in parallel.
 /// * `erasure_count`: This is the number of packets that will be sent to each receiver.
 /// * `num_packet_blast`: This is the number of packets that will be sent to each receiver node in a
-/// single UDP packet.
+///   single UDP packet.
+/// * `send_order`: The order in which the encoded packets are put on the wire.
+/// * `send_order_seed`: Seed for the random number generator used by `SendOrder::Random`.
+/// * `key`: The cluster key verifying the acknowledgements of the receivers.
+/// * `ack_timeout`: How long to wait for the acknowledgements of a batch, 0 to not wait.
 ///
 /// Returns:
 ///
-/// a Result<(), io::Error>
+/// a Result<(), Error>
+#[allow(clippy::too_many_arguments)]
 async fn broadcast_file_in_chunks_to_peers(
     filename: PathBuf,
-    receivers: Vec<NodeAddress>,
+    membership: Arc<Membership>,
+    namespace: SenderNamespace,
+    retransmit: RetransmitConfig,
     num_batches: usize,
     batch_parallelism: usize,
     erasure_count: u32,
     num_packet_blast: usize,
-) -> io::Result<()> {
-    let raw_contents = read_file(filename);
+    send_order: SendOrder,
+    send_order_seed: u64,
+    key: ClusterKey,
+    ack_timeout: Duration,
+) -> Result<(), Error> {
+    let raw_contents = read_file(filename)?;
     println!("Bytes in file: {}", raw_contents.len());
+    // RaptorQ cannot encode an empty payload
+    if raw_contents.is_empty() {
+        return Err(Error::Config(
+            "--file is empty, there is nothing to send".to_string(),
+        ));
+    }
+
+    // Every batch carries the same payload, so it is encoded once and only the header changes
+    let now = SystemTime::now();
+    let encoded = encode_into_packets(&raw_contents, erasure_count);
+    println!(
+        "Encoded {} source and {} repair packets in {:?}",
+        encoded.source.len(),
+        encoded.repair.len(),
+        SystemTime::now().duration_since(now).unwrap_or_default()
+    );
+    if send_order == SendOrder::RepairOnly && encoded.repair.len() < encoded.source.len() {
+        println!(
+            "WARNING: --send-order repair-only sends {} repair packets for {} source symbols, the batches can never be decoded. Raise --erasure-count.",
+            encoded.repair.len(),
+            encoded.source.len()
+        );
+    }
 
     let batch_thread_pool = ThreadPool::new(batch_parallelism);
+    // The threads waiting for the acknowledgements of the batches sent so far
+    let ack_waiters = Arc::new(Mutex::new(vec![]));
+    let mut rng = StdRng::seed_from_u64(send_order_seed);
+    // Batches waiting to be merged into a single interleaved transmission
+    let mut interleave_window: Vec<([u8; BATCH_ID_SIZE], Vec<Vec<u8>>)> = vec![];
 
-    for _ in 0..num_batches {
-        let batch_id = generate_46b_batch_id();
-        let batch_name = str::from_utf8(&batch_id).unwrap();
+    for batch_index in 0..num_batches {
+        let batch_id = namespace.new_batch_id();
+        let batch_name = batch_name(&batch_id).to_string();
         println!("Batch ID {:?}", batch_id);
         println!(
-            "Batch: {} : Encoding packets: {:?}",
+            "Batch: {} : Stamping packets: {:?}",
             batch_name,
-            SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
         );
 
-        let chunked_content = split_into_packets(&raw_contents, batch_id, erasure_count);
+        let chunked_content = split_into_packets(&encoded, batch_id, send_order, &mut rng);
         println!(
             "Batch: {} : Length of each packet: {}",
             batch_name,
-            chunked_content[1].len()
+            chunked_content[0].len()
         );
 
-        let rip = receivers.clone();
+        let (batch_ids, chunked_content) = if send_order == SendOrder::Interleaved {
+            interleave_window.push((batch_id, chunked_content));
+            if interleave_window.len() < batch_parallelism && batch_index + 1 < num_batches {
+                continue;
+            }
+            let (batch_ids, packet_lists) = interleave_window.drain(..).unzip();
+            (batch_ids, interleave_packets(packet_lists))
+        } else {
+            (vec![batch_id], chunked_content)
+        };
+
+        let rip = Arc::new(Topology::new(membership.live_nodes(), retransmit, None));
+        if rip.nodes().is_empty() {
+            return Err(Error::Config("no live receiver to send to".to_string()));
+        }
+        let sent_batch = batch_name.clone();
+        let key = key.clone();
+        let ack_waiters = ack_waiters.clone();
         batch_thread_pool.execute(move || {
-            let _ = broadcast_to_peers(batch_id, rip, chunked_content, num_packet_blast);
+            match broadcast_to_peers(
+                batch_ids,
+                rip,
+                chunked_content,
+                num_packet_blast,
+                key,
+                ack_timeout,
+            ) {
+                Ok(Some(waiter)) => ack_waiters.lock().unwrap().push(waiter),
+                Ok(None) => {}
+                Err(e) => println!("Batch: {} : Failed to send packets: {}", sent_batch, e),
+            }
         });
 
         println!(
             "Batch: {} : Sending packets to recipients: {:?}",
             batch_name,
-            SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
         );
     }
 
     batch_thread_pool.join();
+    for waiter in ack_waiters.lock().unwrap().drain(..) {
+        let _ = waiter.join();
+    }
 
     Ok(())
 }
 
+/// It sends every packet received on the forwarder channel to the peers picked by the retransmit
+/// topology of the live nodes, skipping the peers that announced they have decoded the batch unless
+/// they relay the packet further. The repair symbols minted by this node go to the peers that have
+/// not decoded their batch. Packets over the rate limit of a peer are queued or dropped. The have
+/// batch notices of this node arrive on the same channel and are signed and sent to every peer,
+/// regardless of the rate limit. A failed send is counted and does not stop the forwarding of
+/// later packets.
 #[tokio::main]
+#[allow(clippy::too_many_arguments)]
 async fn forward_handler(
-    forwarder_channel_receive: Receiver<Vec<u8>>,
-    nodes_ips_except_self: Vec<NodeAddress>,
-) -> Result<()> {
+    forwarder_channel_receive: StageReceiver<Forward>,
+    membership: Arc<Membership>,
+    retransmit: RetransmitConfig,
+    identity: SelfIdentity,
+    haves: Arc<PeerHaves>,
+    key: ClusterKey,
+    rate_limit: RateLimit,
+    stats: Arc<ReceiverStats>,
+) -> Result<(), Error> {
     // let _ = udp_socket.set_nonblocking(true);
-    let udp_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await.unwrap());
+    let udp_socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+    // The topology is only rebuilt when the live nodes change
+    let mut membership_version = membership.version();
+    let mut topology = Topology::new(membership.live_nodes(), retransmit, Some(&identity));
+    let mut limiter = PeerRateLimiter::new(rate_limit);
+    // Sends waiting for the budget of their peer, awaited before returning
+    let mut queued_sends: Vec<tokio::task::JoinHandle<()>> = vec![];
     loop {
         match forwarder_channel_receive.recv() {
-            Ok(packet) => {
+            Ok(forward) => {
+                if membership.version() != membership_version {
+                    membership_version = membership.version();
+                    topology = Topology::new(membership.live_nodes(), retransmit, Some(&identity));
+                }
+                // Forwarded packets and minted repair symbols are rate limited and counted once
+                // admitted, notices are neither
+                let (packet, targets, counter): (Vec<u8>, Vec<&NodeAddress>, Option<&AtomicU64>) =
+                    match forward {
+                        Forward::Packet(mut packet) => {
+                            let batch_id = get_batch_id(&packet);
+                            let (targets, suppressed): (Vec<&NodeAddress>, Vec<&NodeAddress>) =
+                                topology.targets(&packet).into_iter().partition(|node| {
+                                    !haves.has(&batch_id, node) || topology.relays(node, &packet)
+                                });
+                            ReceiverStats::add(&stats.packets_suppressed, suppressed.len() as u64);
+                            packet[1] = topology.forward_flag();
+                            (packet, targets, Some(&stats.packets_forwarded))
+                        }
+                        Forward::Have(batch_id) => {
+                            let notice = HaveNotice {
+                                batch_id,
+                                receiver_port: identity.port(),
+                            };
+                            let peers: Vec<&NodeAddress> = topology.peers().collect();
+                            ReceiverStats::add(&stats.have_notices_sent, peers.len() as u64);
+                            (notice.to_bytes(&key), peers, None)
+                        }
+                        Forward::Repair(packet) => {
+                            let batch_id = get_batch_id(&packet);
+                            let lagging: Vec<&NodeAddress> = topology
+                                .peers()
+                                .chain(topology.observers())
+                                .filter(|node| node.role.decodes() && !haves.has(&batch_id, node))
+                                .collect();
+                            (packet, lagging, Some(&stats.reencoded_sent))
+                        }
+                    };
+                queued_sends.retain(|send| !send.is_finished());
                 let mut broadcast_futures: Vec<_> = vec![];
-                for addr in &nodes_ips_except_self {
+                for addr in targets {
+                    let admission = match counter {
+                        Some(_) => limiter.admit(addr, packet.len()),
+                        None => Admission::Now,
+                    };
+                    let delay = match admission {
+                        Admission::Now => None,
+                        Admission::Queued { delay, queued } => Some((delay, queued)),
+                        Admission::Dropped => {
+                            ReceiverStats::increment(&stats.packets_rate_dropped);
+                            continue;
+                        }
+                    };
+                    if let Some(counter) = counter {
+                        ReceiverStats::increment(counter);
+                        ReceiverStats::add(&stats.bytes_forwarded, packet.len() as u64);
+                    }
                     let address = addr.clone();
                     let pack = packet.clone();
                     let sock = udp_socket.clone();
-                    broadcast_futures.push(tokio::task::spawn(async move {
-                        sock.send_to(&pack, (&address.ip_addr[..], address.port))
-                            .await
-                    }))
+                    match delay {
+                        None => broadcast_futures.push(tokio::task::spawn(async move {
+                            sock.send_to(&pack, (&address.ip_addr[..], address.port))
+                                .await
+                        })),
+                        // A queued packet must not hold the packets of the other peers back
+                        Some((delay, queued)) => {
+                            ReceiverStats::increment(&stats.packets_rate_queued);
+                            let send_stats = stats.clone();
+                            queued_sends.push(tokio::task::spawn(async move {
+                                tokio::time::sleep(delay).await;
+                                let sent = sock
+                                    .send_to(&pack, (&address.ip_addr[..], address.port))
+                                    .await;
+                                queued.fetch_sub(1, Ordering::Relaxed);
+                                if let Err(e) = sent {
+                                    send_stats.record_error(&Error::from(e));
+                                }
+                            }));
+                        }
+                    }
+                }
+                for sent in join_all(broadcast_futures).await {
+                    if let Ok(Err(e)) = sent {
+                        stats.record_error(&Error::from(e));
+                    }
                 }
-                let _ = try_join_all(broadcast_futures).await?;
             }
-            Err(e) => {
-                println!("Error occurred while receiving packet: {:?}", e)
+            // Every decoder worker is gone and the queued packets have been forwarded
+            Err(_) => {
+                join_all(queued_sends).await;
+                return Ok(());
             }
         }
     }
 }
 
-async fn process_received_packets(receivers: Vec<NodeAddress>, port: u16) -> io::Result<()> {
-    let sock_recv = &UdpSocket::bind(("0.0.0.0", port)).await.unwrap();
-    println!("Listening on {}", port);
+async fn process_received_packets(
+    membership: Arc<Membership>,
+    role: NodeType,
+    cmd_options: &Arguments,
+    stdout: Option<StreamSink<fs::File>>,
+) -> Result<(), Error> {
+    let port = cmd_options.port;
+    let sock_recv = Arc::new(UdpSocket::bind((cmd_options.bind_ip, port)).await?);
+    let shutdown = shutdown_signal()?;
+    println!("Listening on {} as {:?}", port, role);
+    if !cmd_options.seeds.is_empty() {
+        tokio::spawn(gossip_loop(
+            membership.clone(),
+            sock_recv.clone(),
+            Duration::from_secs(cmd_options.gossip_interval),
+        ));
+    }
+    if membership.liveness().is_some() {
+        tokio::spawn(heartbeat_loop(
+            membership.clone(),
+            sock_recv.clone(),
+            Duration::from_secs(cmd_options.heartbeat_interval),
+            false,
+        ));
+    }
 
     let buf = [0; MTU_SIZE];
 
-    let (reassembler_channel_send, reassembler_channel_receive) = unbounded();
-    let (forwarder_channel_send, forwarder_channel_receive) = unbounded();
-    let (file_creator_send_channel, file_creator_channel_receive) = unbounded();
+    let mut pipeline = PipelineMonitor::new();
+    let (forwarder_channel_send, forwarder_channel_receive) = pipeline.stage(
+        "forward",
+        cmd_options.forward_queue,
+        cmd_options.forward_policy,
+    );
+    let (file_creator_send_channel, file_creator_channel_receive) = pipeline.stage(
+        "writer",
+        cmd_options.writer_queue,
+        cmd_options.writer_policy,
+    );
 
-    let mut batch_id_store: HashSet<[u8; BATCH_ID_SIZE]> = HashSet::new();
-    let mut decoder_hash: HashMap<[u8; BATCH_ID_SIZE], (usize, Decoder)> = HashMap::new();
+    // A batch decoded while the re-encoder is busy gets no minted symbols, the peers still get the
+    // forwarded ones
+    // Only receivers both decode batches and take part in the retransmission
+    let reencode_stage = (cmd_options.reencode && role == NodeType::Receiver).then(|| {
+        pipeline.stage::<([u8; BATCH_ID_SIZE], Vec<u8>)>(
+            "reencode",
+            REENCODE_QUEUE,
+            BackpressurePolicy::DropNewest,
+        )
+    });
+
+    let decoder_workers = cmd_options.decoder_workers.max(1);
+    let max_senders = cmd_options.max_senders;
+    let worker_limits = DecoderLimits {
+        max_batches: cmd_options.max_inflight_batches,
+        max_memory: cmd_options.max_decoder_memory * 1024 * 1024,
+        idle_timeout: Duration::from_secs(cmd_options.batch_idle_timeout),
+    }
+    .per_worker(decoder_workers);
+    let completed_retention = Duration::from_secs(cmd_options.completed_retention);
+    let completed_limit = cmd_options.max_completed_batches.div_ceil(decoder_workers);
+
+    let progress = ProgressTracker::new(decoder_workers);
+    let stats = Arc::new(ReceiverStats::new());
+    let haves = Arc::new(PeerHaves::new(
+        ClusterKey::new(&cmd_options.cluster_key),
+        completed_retention,
+        cmd_options.max_completed_batches,
+    ));
+
+    // Batches are sharded across the decoder workers by batch id, each worker owns the decoders
+    // and the dedup state of its batches
+    let mut reassembler_channels = Vec::with_capacity(decoder_workers);
+    let mut worker_handles = Vec::with_capacity(decoder_workers);
+    for worker in 0..decoder_workers {
+        let (reassembler_channel_send, reassembler_channel_receive) = pipeline.stage(
+            &format!("reassembly-{}", worker),
+            cmd_options.reassembly_queue,
+            cmd_options.reassembly_policy,
+        );
+        reassembler_channels.push(reassembler_channel_send);
 
-    thread::spawn({
-        let assemble_send = reassembler_channel_send.clone();
         let fwd_send = forwarder_channel_send.clone();
+        let receiver_stats = stats.clone();
+        // Relays pass the packets on without decoding them
+        if role == NodeType::Relay {
+            worker_handles.push(thread::spawn(move || {
+                relay_packets(reassembler_channel_receive, fwd_send, receiver_stats)
+            }));
+            continue;
+        }
         let f_send = file_creator_send_channel.clone();
-
-        move || {
+        let batch_progress = progress.clone();
+        let acks = if role.acknowledges() {
+            Some(AckSender::new(
+                ClusterKey::new(&cmd_options.cluster_key),
+                port,
+            )?)
+        } else {
+            None
+        };
+        let reencoder = reencode_stage.as_ref().map(|(send, _)| send.clone());
+        worker_handles.push(thread::spawn(move || {
+            let mut completed_batches = CompletedBatches::new(completed_retention, completed_limit);
+            let mut decoders = SenderDecoders::new(decoder_config(), worker_limits, max_senders);
             reassemble_packets(
                 reassembler_channel_receive,
-                &mut batch_id_store,
-                &mut decoder_hash,
-                fwd_send.clone(),
-                f_send.clone(),
+                &mut completed_batches,
+                &mut decoders,
+                fwd_send,
+                f_send,
+                batch_progress,
+                receiver_stats,
+                acks,
+                reencoder,
             );
-            drop(assemble_send);
-            drop(fwd_send);
-            drop(f_send);
-        }
-    });
+        }));
+    }
+    println!("Decoder workers: {}", reassembler_channels.len());
 
-    thread::spawn(move || file_writer(file_creator_channel_receive));
+    if cmd_options.progress_interval > 0 {
+        let tracker = progress.clone();
+        let receiver_stats = stats.clone();
+        let interval = Duration::from_secs(cmd_options.progress_interval);
+        thread::spawn(move || log_progress(tracker, receiver_stats, pipeline, interval));
+    }
 
-    let nodes_ips_except_self = receivers
-        .iter()
-        .filter(|n| n.role == NodeType::Receiver)
-        .cloned()
-        .collect::<Vec<NodeAddress>>();
+    // Relays write no output, so they do not open the sink at all
+    let writer_handle = if role.decodes() {
+        let sink: Box<dyn OutputSink> = match cmd_options.output {
+            OutputKind::Dir => Box::new(DirectorySink::new(
+                cmd_options.output_dir.clone(),
+                cmd_options.sidecar,
+            )?),
+            OutputKind::Stdout => match stdout {
+                Some(sink) => Box::new(sink),
+                None => {
+                    return Err(Error::Config(
+                        "--output stdout requires the standard output".to_string(),
+                    ))
+                }
+            },
+            OutputKind::Pipe => match &cmd_options.output_pipe {
+                Some(path) => Box::new(StreamSink::pipe(path.clone())?),
+                None => {
+                    return Err(Error::Config(
+                        "--output pipe requires --output-pipe".to_string(),
+                    ))
+                }
+            },
+        };
+        let writer_stats = stats.clone();
+        Some(thread::spawn(move || {
+            file_writer(file_creator_channel_receive, sink, writer_stats)
+        }))
+    } else {
+        None
+    };
 
-    thread::spawn(move || forward_handler(forwarder_channel_receive, nodes_ips_except_self));
+    let identity = SelfIdentity::new(cmd_options.node_id.clone(), cmd_options.bind_ip, port);
+    let reencode_handle = reencode_stage.map(|(_, reencode_receive)| {
+        let fwd_send = forwarder_channel_send.clone();
+        let reencode_membership = membership.clone();
+        let reencode_identity = identity.clone();
+        let reencode_haves = haves.clone();
+        let reencode_stats = stats.clone();
+        let symbols = cmd_options.reencode_symbols;
+        let range = cmd_options.repair_range;
+        thread::spawn(move || {
+            reencode_handler(
+                reencode_receive,
+                fwd_send,
+                reencode_membership,
+                reencode_identity,
+                reencode_haves,
+                symbols,
+                range,
+                reencode_stats,
+            )
+        })
+    });
+
+    let forwarder_membership = membership.clone();
+    let retransmit = cmd_options.retransmit_config();
+    let forwarder_haves = haves.clone();
+    let forwarder_key = ClusterKey::new(&cmd_options.cluster_key);
+    let rate_limit = cmd_options.rate_limit();
+    let forwarder_stats = stats.clone();
+    let forward_handle = thread::spawn(move || {
+        if let Err(e) = forward_handler(
+            forwarder_channel_receive,
+            forwarder_membership,
+            retransmit,
+            identity,
+            forwarder_haves,
+            forwarder_key,
+            rate_limit,
+            forwarder_stats,
+        ) {
+            println!("Forwarder stopped: {}", e);
+        }
+    });
 
+    let mut loss_rng = StdRng::from_entropy();
+    tokio::pin!(shutdown);
     loop {
         let mut receive_buffers = [buf; NUM_RCVMMSGS];
 
         // Receiving a batch of packets from the socket.
-        let res = recv_mmsg(sock_recv, receive_buffers.borrow_mut())
-            .await
-            .unwrap();
-
-        if res.len() > 0 {
-            let mut i = 0;
-            for buf in &receive_buffers {
-                let packets_info = res.get(i).unwrap();
-                let _ = reassembler_channel_send.send((buf.clone(), packets_info.1));
-                i = i + 1;
+        let res = tokio::select! {
+            res = recv_mmsg(&sock_recv, receive_buffers.borrow_mut()) => res,
+            _ = &mut shutdown => break,
+        };
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                stats.record_error(&Error::from(e));
+                continue;
             }
+        };
+
+        for (index, nrecv, from) in res {
+            let packet = receive_buffers[index];
+            if packet[0] == HAVE_KIND {
+                if let Err(e) = haves.handle_message(&packet[..nrecv], from) {
+                    if stats.record_rejection(from, &e) {
+                        println!("Rejected datagram from {}: {}", from, e);
+                    }
+                }
+                continue;
+            }
+            if packet[0] == HEARTBEAT_KIND {
+                match membership.handle_heartbeat(&packet[..nrecv], from) {
+                    Ok(Some(reply)) => {
+                        if let Err(e) = sock_recv.send_to(&reply, from).await {
+                            stats.record_error(&Error::from(e));
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        if stats.record_rejection(from, &e) {
+                            println!("Rejected datagram from {}: {}", from, e);
+                        }
+                    }
+                }
+                continue;
+            }
+            if packet[0] == PEER_RECORDS_KIND {
+                match membership.handle_message(&packet[..nrecv]) {
+                    Ok(replies) => {
+                        for reply in replies {
+                            if let Err(e) = sock_recv.send_to(&reply, from).await {
+                                stats.record_error(&Error::from(e));
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        if stats.record_rejection(from, &e) {
+                            println!("Rejected datagram from {}: {}", from, e);
+                        }
+                    }
+                }
+                continue;
+            }
+            if cmd_options.simulated_loss > 0.0 && loss_rng.gen_bool(cmd_options.simulated_loss) {
+                ReceiverStats::increment(&stats.packets_lost_simulated);
+                continue;
+            }
+            let worker = decoder_worker_index(&get_batch_id(&packet), reassembler_channels.len());
+            let _ = reassembler_channels[worker].send((packet, nrecv, from));
         }
     }
+
+    // Every stage exits once the stages feeding it are gone and its queue is drained
+    println!("Shutting down: draining the pipeline");
+    drop(reassembler_channels);
+    drop(forwarder_channel_send);
+    drop(file_creator_send_channel);
+    for handle in worker_handles {
+        let _ = handle.join();
+    }
+    if let Some(handle) = reencode_handle {
+        let _ = handle.join();
+    }
+    let _ = forward_handle.join();
+    if let Some(handle) = writer_handle {
+        let _ = handle.join();
+    }
+
+    let summary = RunSummary::new(&stats, progress.snapshot().len());
+    println!("Summary: {}", summary);
+    for (namespace, sender) in &summary.senders {
+        println!("Summary: Sender: {}: {}", namespace, sender);
+    }
+    if let Some(summary_file) = &cmd_options.summary_file {
+        let contents = serde_yaml::to_string(&summary).map_err(io::Error::other)?;
+        fs::write(summary_file, contents)?;
+    }
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    let cmd_options = Arguments::parse();
-    let receivers = vec![
+/// It listens for SIGINT and SIGTERM
+///
+/// Returns:
+///
+/// A future resolving once the process is asked to stop
+fn shutdown_signal() -> Result<impl Future<Output = ()>, Error> {
+    #[cfg(unix)]
+    let mut sigterm = signal(SignalKind::terminate())?;
+    Ok(async move {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = sigterm.recv() => {},
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    })
+}
+
+/// The three local receivers used when no `--nodes-config` is given
+fn default_nodes() -> Vec<NodeAddress> {
+    vec![
         NodeAddress {
             ip_addr: "0.0.0.0".to_string(),
             port: 1235,
             role: NodeType::Receiver,
+            weight: default_weight(),
+            id: None,
+            addresses: vec![],
         },
         NodeAddress {
             ip_addr: "0.0.0.0".to_string(),
             port: 1236,
             role: NodeType::Receiver,
+            weight: default_weight(),
+            id: None,
+            addresses: vec![],
         },
         NodeAddress {
             ip_addr: "0.0.0.0".to_string(),
             port: 1237,
             role: NodeType::Receiver,
+            weight: default_weight(),
+            id: None,
+            addresses: vec![],
         },
-    ];
+    ]
+}
+
+/// It reads the cluster nodes from a YAML list of `ip`, `port`, `role` and optional `weight`
+fn read_nodes_config(path: &Path) -> Result<Vec<NodeAddress>, Error> {
+    let nodes: Vec<NodeAddress> = serde_yaml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| Error::Config(format!("{:?}: {}", path, e)))?;
+    if !nodes.iter().any(|n| n.role == NodeType::Receiver) {
+        return Err(Error::Config(format!("{:?} lists no receiver", path)));
+    }
+    Ok(nodes)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let cmd_options = Arguments::parse();
+    if let Some(Command::Bench(bench_options)) = cmd_options.command {
+        return Ok(run_bench(bench_options)?);
+    }
+    // Peer records carry the id and the ip with a single byte length
+    if cmd_options
+        .node_id
+        .as_ref()
+        .is_some_and(|id| id.len() > u8::MAX as usize)
+    {
+        return Err(Error::Config(
+            "--node-id must be at most 255 bytes".to_string(),
+        ));
+    }
+    if cmd_options.advertise_ip.len() > u8::MAX as usize {
+        return Err(Error::Config(
+            "--advertise-ip must be at most 255 bytes".to_string(),
+        ));
+    }
+    if cmd_options.send_order == SendOrder::RepairOnly && cmd_options.erasure_count == 0 {
+        return Err(Error::Config(
+            "--send-order repair-only sends no packet with --erasure-count 0".to_string(),
+        ));
+    }
+    if !(0.0..1.0).contains(&cmd_options.simulated_loss) {
+        return Err(Error::Config(
+            "--simulated-loss must be in the range [0, 1)".to_string(),
+        ));
+    }
+    if cmd_options
+        .repair_range
+        .is_some_and(|range| range >= NODES_WITH_REPAIR_RANGE)
+    {
+        return Err(Error::Config(format!(
+            "--repair-range must be below {}",
+            NODES_WITH_REPAIR_RANGE
+        )));
+    }
+    // The batches take the standard output over before anything is logged to it
+    let stdout = if !cmd_options.send && cmd_options.output == OutputKind::Stdout {
+        Some(StreamSink::stdout()?)
+    } else {
+        None
+    };
+    if cmd_options.cluster_key == DEFAULT_CLUSTER_KEY {
+        println!(
+            "WARNING: --cluster-key is the well-known default, anyone can forge acknowledgements, peer records and heartbeats. Set a secret shared by the nodes outside of local tests."
+        );
+    }
+    let receivers = match &cmd_options.nodes_config {
+        Some(path) => read_nodes_config(path)?,
+        None => default_nodes(),
+    };
+    let key = ClusterKey::new(&cmd_options.cluster_key);
+    let peer_ttl = Duration::from_secs(cmd_options.peer_ttl);
 
     if cmd_options.send {
-        let filename = match cmd_options.input_file {
-            None => {
-                println!("missing file name argument");
-                return Ok(());
-            }
+        let filename = match cmd_options.input_file.clone() {
+            None => return Err(Error::Config("missing file name argument".to_string())),
             Some(path) => {
                 if !Path::new(&path).exists() {
-                    println!("{:?} does not exit", path);
-                    return Ok(());
+                    return Err(Error::Config(format!("{:?} does not exist", path)));
                 }
                 path
             }
         };
 
+        let own = NodeAddress {
+            ip_addr: cmd_options.advertise_ip.clone(),
+            port: cmd_options.port,
+            role: NodeType::Sender,
+            weight: cmd_options.weight,
+            id: cmd_options.node_id.clone(),
+            addresses: cmd_options
+                .other_ips
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect(),
+        };
+        // A sender listed in the node list names its batches after its entry, so that receivers
+        // can tell the batches of concurrent senders apart
+        let identity = SelfIdentity::new(
+            cmd_options.node_id.clone(),
+            cmd_options.bind_ip,
+            cmd_options.port,
+        );
+        let senders: Vec<&NodeAddress> = receivers
+            .iter()
+            .filter(|node| node.role == NodeType::Sender)
+            .collect();
+        let namespace = match senders.iter().find(|node| identity.is(node)) {
+            Some(entry) => SenderNamespace::of(entry),
+            None if !senders.is_empty() => {
+                return Err(Error::Config(
+                    "this node is none of the senders of the node list, see --node-id and -p"
+                        .to_string(),
+                ))
+            }
+            None => SenderNamespace::of(&own),
+        };
+        println!("Sender namespace: {}", namespace);
+
+        // The sender answers peer records and heartbeats on its own socket, it only joins the
+        // cluster to learn its members and which of them are alive
+        let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
+        let membership = if cmd_options.seeds.is_empty() {
+            Membership::fixed(receivers, key.clone())
+        } else {
+            let own = NodeAddress {
+                port: socket.local_addr()?.port(),
+                ..own
+            };
+            Membership::discovered(own, cmd_options.seeds.clone(), key.clone(), peer_ttl)
+        };
+        let membership = Arc::new(match cmd_options.liveness(identity) {
+            Some(liveness) => membership.with_liveness(liveness),
+            None => membership,
+        });
+        tokio::spawn(answer_loop(membership.clone(), socket.clone()));
+        if !cmd_options.seeds.is_empty() {
+            tokio::spawn(gossip_loop(
+                membership.clone(),
+                socket.clone(),
+                Duration::from_secs(cmd_options.gossip_interval),
+            ));
+            tokio::time::sleep(Duration::from_secs(cmd_options.discovery_wait)).await;
+        }
+        if let Some(liveness) = membership.liveness() {
+            tokio::spawn(heartbeat_loop(
+                membership.clone(),
+                socket,
+                Duration::from_secs(cmd_options.heartbeat_interval),
+                true,
+            ));
+            // Receivers that do not answer the first heartbeats get no shard from the first batch
+            let deadline = Instant::now() + Duration::from_secs(cmd_options.heartbeat_timeout);
+            while Instant::now() < deadline && !liveness.all_heard(&membership.known_nodes()) {
+                tokio::time::sleep(HEARTBEAT_POLL_INTERVAL).await;
+            }
+            membership.check_liveness();
+        }
+
         println!(
             "Number of batches: {:?}: batches parallelism: {:?}: erasure chunks: {}: Number of packet blasts: {}: Number of receivers: {}",
-            cmd_options.num_batches, cmd_options.batch_parallelism, cmd_options.erasure_count, cmd_options.num_packet_blast, receivers.len()
+            cmd_options.num_batches, cmd_options.batch_parallelism, cmd_options.erasure_count, cmd_options.num_packet_blast,
+            membership.live_nodes().iter().filter(|n| n.role == NodeType::Receiver).count()
         );
 
         broadcast_file_in_chunks_to_peers(
             filename,
-            receivers,
+            membership,
+            namespace,
+            cmd_options.retransmit_config(),
             cmd_options.num_batches,
             cmd_options.batch_parallelism,
             cmd_options.erasure_count,
             cmd_options.num_packet_blast,
+            cmd_options.send_order,
+            cmd_options.send_order_seed,
+            key,
+            Duration::from_secs(cmd_options.ack_timeout),
         )
         .await
     } else {
-        process_received_packets(receivers, cmd_options.port).await
+        let identity = SelfIdentity::new(
+            cmd_options.node_id.clone(),
+            cmd_options.bind_ip,
+            cmd_options.port,
+        );
+        // A node in the node list has the role of its entry, a discovered one advertises --role
+        let entry = receivers.iter().find(|node| identity.is(node));
+        let role = match (cmd_options.seeds.is_empty(), entry, cmd_options.role) {
+            (true, Some(entry), Some(role)) if entry.role != role => {
+                return Err(Error::Config(format!(
+                    "--role {:?} differs from the {:?} role of this node in the node list",
+                    role, entry.role
+                )))
+            }
+            (true, Some(entry), _) => entry.role,
+            (_, _, role) => role.unwrap_or_default(),
+        };
+        if role == NodeType::Sender {
+            return Err(Error::Config("a sender is started with --send".to_string()));
+        }
+        let membership = if cmd_options.seeds.is_empty() {
+            Membership::fixed(receivers, key)
+        } else {
+            let own = NodeAddress {
+                ip_addr: cmd_options.advertise_ip.clone(),
+                port: cmd_options.port,
+                role,
+                weight: cmd_options.weight,
+                id: cmd_options.node_id.clone(),
+                addresses: cmd_options
+                    .other_ips
+                    .iter()
+                    .map(|ip| ip.to_string())
+                    .collect(),
+            };
+            Membership::discovered(own, cmd_options.seeds.clone(), key, peer_ttl)
+        };
+        let membership = match cmd_options.liveness(identity) {
+            Some(liveness) => membership.with_liveness(liveness),
+            None => membership,
+        };
+        process_received_packets(Arc::new(membership), role, &cmd_options, stdout).await
     }
 }
 
@@ -287,6 +1024,9 @@ async fn main() -> io::Result<()> {
     author = "vrrb"
 )]
 pub struct Arguments {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
     #[clap(short = 's', long = "send", help = "Send raw bytes from file")]
     pub send: bool,
 
@@ -340,9 +1080,449 @@ pub struct Arguments {
         default_value_t = 32
     )]
     pub num_packet_blast: usize,
+
+    #[clap(
+        value_name = "decoder-workers",
+        long = "decoder-workers",
+        help = "number of threads decoding batches on the receiver",
+        default_value_t = 1
+    )]
+    pub decoder_workers: usize,
+
+    #[clap(
+        value_name = "progress-interval",
+        long = "progress-interval",
+        help = "seconds between per-batch decode progress reports, 0 disables them",
+        default_value_t = 5
+    )]
+    pub progress_interval: u64,
+
+    #[clap(
+        value_name = "max-inflight-batches",
+        long = "max-inflight-batches",
+        help = "maximum number of batches decoded at the same time, of all the senders together",
+        default_value_t = 64
+    )]
+    pub max_inflight_batches: usize,
+
+    #[clap(
+        value_name = "max-senders",
+        long = "max-senders",
+        help = "maximum number of senders whose batches are decoded at the same time",
+        default_value_t = 16
+    )]
+    pub max_senders: usize,
+
+    #[clap(
+        value_name = "max-decoder-memory",
+        long = "max-decoder-memory",
+        help = "maximum MiB of received symbols held by the decoders of all the senders together",
+        default_value_t = 1024
+    )]
+    pub max_decoder_memory: usize,
+
+    #[clap(
+        value_name = "batch-idle-timeout",
+        long = "batch-idle-timeout",
+        help = "seconds without a packet after which an undecoded batch is dropped",
+        default_value_t = 60
+    )]
+    pub batch_idle_timeout: u64,
+
+    #[clap(
+        value_name = "completed-retention",
+        long = "completed-retention",
+        help = "seconds a decoded batch id is remembered to drop its late packets",
+        default_value_t = 600
+    )]
+    pub completed_retention: u64,
+
+    #[clap(
+        value_name = "max-completed-batches",
+        long = "max-completed-batches",
+        help = "maximum number of decoded batch ids remembered",
+        default_value_t = 100000
+    )]
+    pub max_completed_batches: usize,
+
+    #[clap(
+        value_name = "reassembly-queue",
+        long = "reassembly-queue",
+        help = "packets queued for each decoder worker, 0 for unbounded",
+        default_value_t = 0
+    )]
+    pub reassembly_queue: usize,
+
+    #[clap(
+        value_name = "reassembly-policy",
+        long = "reassembly-policy",
+        help = "what to do with packets when a decoder worker queue is full",
+        arg_enum,
+        default_value = "block"
+    )]
+    pub reassembly_policy: BackpressurePolicy,
+
+    #[clap(
+        value_name = "forward-queue",
+        long = "forward-queue",
+        help = "packets queued for forwarding, 0 for unbounded",
+        default_value_t = 0
+    )]
+    pub forward_queue: usize,
+
+    #[clap(
+        value_name = "forward-policy",
+        long = "forward-policy",
+        help = "what to do with packets when the forward queue is full",
+        arg_enum,
+        default_value = "block"
+    )]
+    pub forward_policy: BackpressurePolicy,
+
+    #[clap(
+        long = "reencode",
+        help = "re-encode the batches this node decoded and send fresh repair symbols to the peers still decoding them"
+    )]
+    pub reencode: bool,
+
+    #[clap(
+        value_name = "reencode-symbols",
+        long = "reencode-symbols",
+        help = "repair symbols minted per decoded batch with --reencode, at most 4096",
+        default_value_t = 1024
+    )]
+    pub reencode_symbols: u32,
+
+    #[clap(
+        value_name = "repair-range",
+        long = "repair-range",
+        help = "range of repair symbol ids this node re-encodes from, below 3839, instead of its position in the node list or a hash of its name"
+    )]
+    pub repair_range: Option<u32>,
+
+    #[clap(
+        value_name = "simulated-loss",
+        long = "simulated-loss",
+        help = "probability of dropping each received data packet, to simulate a lossy link",
+        default_value_t = 0.0
+    )]
+    pub simulated_loss: f64,
+
+    #[clap(
+        value_name = "peer-rate",
+        long = "peer-rate",
+        help = "maximum packets or bytes per second forwarded to every peer, 0 for unlimited",
+        default_value_t = 0
+    )]
+    pub peer_rate: u64,
+
+    #[clap(
+        value_name = "peer-rate-unit",
+        long = "peer-rate-unit",
+        help = "unit of --peer-rate and --peer-burst",
+        arg_enum,
+        default_value = "packets"
+    )]
+    pub peer_rate_unit: RateUnit,
+
+    #[clap(
+        value_name = "peer-burst",
+        long = "peer-burst",
+        help = "packets or bytes forwarded to a peer at once after an idle period, 0 for one second of --peer-rate",
+        default_value_t = 0
+    )]
+    pub peer_burst: u64,
+
+    #[clap(
+        value_name = "peer-rate-policy",
+        long = "peer-rate-policy",
+        help = "what to do with packets over the rate of their peer",
+        arg_enum,
+        default_value = "queue"
+    )]
+    pub peer_rate_policy: RatePolicy,
+
+    #[clap(
+        value_name = "peer-queue",
+        long = "peer-queue",
+        help = "packets held for a single peer with --peer-rate-policy queue, the next ones are dropped",
+        default_value_t = 1024
+    )]
+    pub peer_queue: usize,
+
+    #[clap(
+        value_name = "writer-queue",
+        long = "writer-queue",
+        help = "decoded batches queued for writing, 0 for unbounded",
+        default_value_t = 0
+    )]
+    pub writer_queue: usize,
+
+    #[clap(
+        value_name = "writer-policy",
+        long = "writer-policy",
+        help = "what to do with decoded batches when the writer queue is full",
+        arg_enum,
+        default_value = "block"
+    )]
+    pub writer_policy: BackpressurePolicy,
+
+    #[clap(
+        value_name = "output",
+        long = "output",
+        help = "where decoded batches are written",
+        arg_enum,
+        default_value = "dir"
+    )]
+    pub output: OutputKind,
+
+    #[clap(
+        value_name = "output-pipe",
+        long = "output-pipe",
+        help = "named pipe decoded batches are written to with --output pipe",
+        parse(from_os_str)
+    )]
+    pub output_pipe: Option<PathBuf>,
+
+    #[clap(
+        value_name = "output-dir",
+        long = "output-dir",
+        help = "directory decoded batches are written to with --output dir",
+        parse(from_os_str),
+        default_value = "."
+    )]
+    pub output_dir: PathBuf,
+
+    #[clap(
+        long = "sidecar",
+        help = "write a .meta file with the size and SHA-256 of every decoded batch"
+    )]
+    pub sidecar: bool,
+
+    #[clap(
+        value_name = "summary-file",
+        long = "summary-file",
+        help = "YAML file the receiver writes its end-of-run summary to",
+        parse(from_os_str)
+    )]
+    pub summary_file: Option<PathBuf>,
+
+    #[clap(
+        value_name = "cluster-key",
+        long = "cluster-key",
+        help = "secret shared by the nodes to sign the messages they exchange",
+        default_value = DEFAULT_CLUSTER_KEY
+    )]
+    pub cluster_key: String,
+
+    #[clap(
+        value_name = "ack-timeout",
+        long = "ack-timeout",
+        help = "seconds the sender waits for the receivers to acknowledge a batch, 0 to not wait",
+        default_value_t = 10
+    )]
+    pub ack_timeout: u64,
+
+    #[clap(
+        arg_enum,
+        value_name = "retransmit",
+        long = "retransmit",
+        help = "how receivers retransmit the packets they get, the sender and every receiver must agree",
+        default_value = "all-to-all"
+    )]
+    pub retransmit: RetransmitMode,
+
+    #[clap(
+        value_name = "fanout",
+        long = "fanout",
+        help = "number of children of every node with --retransmit tree",
+        default_value_t = 2
+    )]
+    pub fanout: usize,
+
+    #[clap(
+        arg_enum,
+        value_name = "shard-assignment",
+        long = "shard-assignment",
+        help = "how the packets of a batch are spread over the receivers, the sender and every receiver must agree",
+        default_value = "round-robin"
+    )]
+    pub shard_assignment: ShardAssignment,
+
+    #[clap(
+        value_name = "nodes-config",
+        long = "nodes-config",
+        help = "YAML list of the cluster nodes, with their ip, port, role and weight",
+        parse(from_os_str)
+    )]
+    pub nodes_config: Option<PathBuf>,
+
+    #[clap(
+        value_name = "seeds",
+        long = "seeds",
+        help = "comma separated addresses of nodes to discover the cluster from, instead of the node list",
+        use_value_delimiter = true
+    )]
+    pub seeds: Vec<SocketAddr>,
+
+    #[clap(
+        value_name = "role",
+        long = "role",
+        arg_enum,
+        help = "role of this node when it is discovered or not in the node list [default: receiver]"
+    )]
+    pub role: Option<NodeType>,
+
+    #[clap(
+        value_name = "node-id",
+        long = "node-id",
+        help = "id of this node in the node list, to recognize itself by instead of its bound address"
+    )]
+    pub node_id: Option<String>,
+
+    #[clap(
+        value_name = "bind-ip",
+        long = "bind-ip",
+        help = "IP address the receiver listens on",
+        default_value = "0.0.0.0"
+    )]
+    pub bind_ip: IpAddr,
+
+    #[clap(
+        value_name = "other-ips",
+        long = "other-ips",
+        help = "comma separated other IP addresses this node is reachable at, advertised with --seeds",
+        use_value_delimiter = true
+    )]
+    pub other_ips: Vec<IpAddr>,
+
+    #[clap(
+        value_name = "advertise-ip",
+        long = "advertise-ip",
+        help = "IP address the other nodes reach this node at, with --seeds",
+        default_value = "127.0.0.1"
+    )]
+    pub advertise_ip: String,
+
+    #[clap(
+        value_name = "weight",
+        long = "weight",
+        help = "weight this node advertises for weighted shard assignment, with --seeds",
+        default_value_t = 1
+    )]
+    pub weight: u64,
+
+    #[clap(
+        value_name = "gossip-interval",
+        long = "gossip-interval",
+        help = "seconds between two rounds of peer records sent to the other nodes",
+        default_value_t = 1
+    )]
+    pub gossip_interval: u64,
+
+    #[clap(
+        value_name = "peer-ttl",
+        long = "peer-ttl",
+        help = "seconds after which a node that stopped refreshing its peer record is dropped",
+        default_value_t = 10
+    )]
+    pub peer_ttl: u64,
+
+    #[clap(
+        value_name = "discovery-wait",
+        long = "discovery-wait",
+        help = "seconds the sender spends discovering the cluster before sending",
+        default_value_t = 3
+    )]
+    pub discovery_wait: u64,
+
+    #[clap(
+        value_name = "heartbeat-interval",
+        long = "heartbeat-interval",
+        help = "seconds between two heartbeats sent to the receivers, 0 disables liveness tracking",
+        default_value_t = 1
+    )]
+    pub heartbeat_interval: u64,
+
+    #[clap(
+        value_name = "heartbeat-timeout",
+        long = "heartbeat-timeout",
+        help = "seconds without a heartbeat after which a receiver gets no shard and no forwarded packet",
+        default_value_t = 3
+    )]
+    pub heartbeat_timeout: u64,
+
+    #[clap(
+        value_name = "send-order",
+        long = "send-order",
+        help = "order in which encoded packets are sent",
+        arg_enum,
+        default_value = "source-then-repair"
+    )]
+    pub send_order: SendOrder,
+
+    #[clap(
+        value_name = "send-order-seed",
+        long = "send-order-seed",
+        help = "seed used to shuffle packets with --send-order random",
+        default_value_t = 0
+    )]
+    pub send_order_seed: u64,
+}
+
+impl Arguments {
+    /// Budget of the packets forwarded to every peer
+    pub fn rate_limit(&self) -> RateLimit {
+        RateLimit {
+            rate: self.peer_rate,
+            unit: self.peer_rate_unit,
+            burst: self.peer_burst,
+            policy: self.peer_rate_policy,
+            max_queued: self.peer_queue,
+        }
+    }
+
+    /// The liveness table of the receivers, unless heartbeats are disabled
+    pub(crate) fn liveness(&self, identity: SelfIdentity) -> Option<Liveness> {
+        (self.heartbeat_interval > 0).then(|| {
+            Liveness::new(
+                identity,
+                ClusterKey::new(&self.cluster_key),
+                Duration::from_secs(self.heartbeat_timeout),
+            )
+        })
+    }
+
+    /// How packets are spread over the receivers and retransmitted
+    pub fn retransmit_config(&self) -> RetransmitConfig {
+        RetransmitConfig {
+            mode: self.retransmit,
+            assignment: self.shard_assignment,
+            fanout: self.fanout,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Measure encode and decode throughput without starting a network
+    Bench(BenchArguments),
+}
+
+/// Order in which the sender puts the encoded packets of a batch on the wire
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOrder {
+    /// All source symbols first, then the repair symbols
+    SourceThenRepair,
+    /// Only the repair symbols, no source symbols at all
+    RepairOnly,
+    /// Source and repair symbols shuffled with a seeded generator
+    Random,
+    /// Source-then-repair per batch, with the packets of concurrent batches sent round-robin
+    Interleaved,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename = "node_list")]
 pub(crate) struct NodeAddress {
     /// IP address of the node
@@ -352,13 +1532,72 @@ pub(crate) struct NodeAddress {
     pub port: u16,
     /// Leader, Normal
     pub role: NodeType,
+    /// Relative share of the packets the node gets with weighted shard assignment, such as its
+    /// stake or its bandwidth
+    #[serde(default = "default_weight")]
+    pub weight: u64,
+    /// Id the node recognizes itself by when started with the same `--node-id`
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Other IP addresses of a multi-homed node, besides `ip`
+    #[serde(default)]
+    pub addresses: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+fn default_weight() -> u64 {
+    1
+}
+
+impl NodeAddress {
+    /// The id of the node, or its address without one, the same on every node that knows it
+    pub fn name(&self) -> String {
+        match &self.id {
+            Some(id) => id.clone(),
+            None => format!("{}:{}", self.ip_addr, self.port),
+        }
+    }
+
+    /// Whether a node listening on `port` at `ip` is this node, at any of its addresses. Nodes
+    /// listed with an unspecified address are matched on their port only.
+    pub fn is_at(&self, ip: IpAddr, port: u16) -> bool {
+        self.port == port
+            && std::iter::once(&self.ip_addr)
+                .chain(&self.addresses)
+                .any(|address| match address.parse::<IpAddr>() {
+                    Ok(own) => own.is_unspecified() || own == ip,
+                    Err(_) => false,
+                })
+    }
+}
+
+#[derive(ArgEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum NodeType {
     Sender,
+    /// Decodes the batches and forwards packets along the retransmit topology
     Receiver,
+    /// Forwards packets along the retransmit topology, but never decodes nor writes output
+    Relay,
+    /// Decodes every batch from the packets it is sent, but never forwards
+    Observer,
+}
+
+impl NodeType {
+    /// Whether nodes of the role get packets from the sender and forward them to the others
+    pub fn retransmits(&self) -> bool {
+        matches!(self, NodeType::Receiver | NodeType::Relay)
+    }
+
+    /// Whether nodes of the role decode the batches
+    pub fn decodes(&self) -> bool {
+        matches!(self, NodeType::Receiver | NodeType::Observer)
+    }
+
+    /// Whether nodes of the role acknowledge the batches they decode to the sender. Observers only
+    /// get forwarded packets and never learn the address of the sender.
+    pub fn acknowledges(&self) -> bool {
+        matches!(self, NodeType::Receiver)
+    }
 }
 
 impl Default for NodeType {
@@ -416,18 +1655,16 @@ pub async fn recv_mmsg(
     packets: &mut [[u8; 1280]; NUM_RCVMMSGS],
 ) -> io::Result<Vec<(usize, usize, SocketAddr)>> {
     let mut received = Vec::new();
-    let count = std::cmp::min(NUM_RCVMMSGS, packets.len());
-    let mut i = 0;
-    for packt in packets.iter_mut().take(count) {
-        match socket.recv_from(packt).await {
-            Err(e) => {
-                return Err(e);
-            }
-            Ok((nrecv, from)) => {
-                received.push((i, nrecv, from));
-            }
+    // It waits for the first packet only, then takes whatever else is already queued, so that a
+    // lone control message is not held until more packets arrive
+    let (nrecv, from) = socket.recv_from(&mut packets[0]).await?;
+    received.push((0, nrecv, from));
+    for (i, packt) in packets.iter_mut().enumerate().skip(1) {
+        match socket.try_recv_from(packt) {
+            Ok((nrecv, from)) => received.push((i, nrecv, from)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
         }
-        i += 1;
     }
     Ok(received)
 }
@@ -0,0 +1,57 @@
+use crate::{NodeAddress, BATCH_ID_SIZE};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Number of leading batch id characters naming the sender of the batch
+pub const NAMESPACE_SIZE: usize = 8;
+
+/// The prefix of the batch ids of a sender, so that the batches of concurrent senders never collide
+/// and receivers can tell them apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SenderNamespace([u8; NAMESPACE_SIZE]);
+
+impl SenderNamespace {
+    /// The namespace of a sender node, derived from its id, or from its address without one
+    pub fn of(node: &NodeAddress) -> Self {
+        let digest = Sha256::digest(node.name().as_bytes());
+        let mut namespace = [0; NAMESPACE_SIZE];
+        // Hex digits keep the batch id alphanumeric
+        for (i, byte) in namespace.iter_mut().enumerate() {
+            let nibble = (digest[i / 2] >> (4 * (1 - i % 2))) & 0xf;
+            *byte = b"0123456789abcdef"[nibble as usize];
+        }
+        SenderNamespace(namespace)
+    }
+
+    /// The namespace a batch id belongs to
+    pub fn of_batch(batch_id: &[u8; BATCH_ID_SIZE]) -> Self {
+        let mut namespace = [0; NAMESPACE_SIZE];
+        namespace.copy_from_slice(&batch_id[..NAMESPACE_SIZE]);
+        SenderNamespace(namespace)
+    }
+
+    /// A random batch id within the namespace
+    pub fn new_batch_id(&self) -> [u8; BATCH_ID_SIZE] {
+        let mut batch_id = [0; BATCH_ID_SIZE];
+        batch_id[..NAMESPACE_SIZE].copy_from_slice(&self.0);
+        for (byte, random) in batch_id[NAMESPACE_SIZE..]
+            .iter_mut()
+            .zip(rand::thread_rng().sample_iter(&Alphanumeric))
+        {
+            *byte = random;
+        }
+        batch_id
+    }
+}
+
+impl fmt::Display for SenderNamespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            std::str::from_utf8(&self.0).unwrap_or("<non-utf8>")
+        )
+    }
+}
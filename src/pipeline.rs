@@ -0,0 +1,226 @@
+use clap::ArgEnum;
+use crossbeam_channel::{
+    bounded, unbounded, Receiver, SendError, SendTimeoutError, Sender, TrySendError,
+};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+/// How often a sender blocked on a full queue checks that the next stage is still there
+const CONSUMER_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a pipeline stage does with a new item when its queue is full
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Wait until the next stage makes room
+    Block,
+    /// Discard the new item
+    DropNewest,
+    /// Discard the oldest queued item to make room for the new one
+    DropOldest,
+}
+
+/// Counters of a single pipeline stage
+#[derive(Debug, Default)]
+pub struct StageStats {
+    /// Items handed to the stage
+    pub sent: AtomicU64,
+    /// Items discarded because the stage queue was full
+    pub dropped: AtomicU64,
+}
+
+/// Sending half of a channel between two pipeline stages, applying the stage backpressure policy
+/// when the queue is full
+pub struct StageSender<T> {
+    sender: Sender<T>,
+    /// Kept to drop the oldest item, only with the drop oldest policy
+    oldest: Option<Receiver<T>>,
+    /// Alive as long as the next stage holds its receiving half. The receivers kept here and by
+    /// the monitor keep the channel itself connected, so they cannot tell.
+    consumer: Weak<()>,
+    policy: BackpressurePolicy,
+    stats: Arc<StageStats>,
+}
+
+impl<T> Clone for StageSender<T> {
+    fn clone(&self) -> Self {
+        StageSender {
+            sender: self.sender.clone(),
+            oldest: self.oldest.clone(),
+            consumer: self.consumer.clone(),
+            policy: self.policy,
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+/// Receiving half of a channel between two pipeline stages. The stage is gone for its senders once
+/// every clone is dropped.
+pub struct StageReceiver<T> {
+    receiver: Receiver<T>,
+    _consumer: Arc<()>,
+}
+
+impl<T> Clone for StageReceiver<T> {
+    fn clone(&self) -> Self {
+        StageReceiver {
+            receiver: self.receiver.clone(),
+            _consumer: self._consumer.clone(),
+        }
+    }
+}
+
+impl<T> Deref for StageReceiver<T> {
+    type Target = Receiver<T>;
+
+    fn deref(&self) -> &Receiver<T> {
+        &self.receiver
+    }
+}
+
+impl<T> StageSender<T> {
+    /// It queues an item for the next stage according to the backpressure policy. Dropping an
+    /// item is not an error, it is only counted.
+    ///
+    /// Returns:
+    ///
+    /// An error only once the next stage is gone, every `StageReceiver` of it dropped
+    pub fn send(&self, item: T) -> Result<(), SendError<T>> {
+        if self.consumer.strong_count() == 0 {
+            return Err(SendError(item));
+        }
+        self.stats.sent.fetch_add(1, Ordering::Relaxed);
+        match self.policy {
+            BackpressurePolicy::Block => {
+                let mut item = item;
+                loop {
+                    match self.sender.send_timeout(item, CONSUMER_CHECK_INTERVAL) {
+                        Ok(()) => return Ok(()),
+                        Err(SendTimeoutError::Timeout(rejected)) => {
+                            if self.consumer.strong_count() == 0 {
+                                return Err(SendError(rejected));
+                            }
+                            item = rejected;
+                        }
+                        Err(SendTimeoutError::Disconnected(item)) => return Err(SendError(item)),
+                    }
+                }
+            }
+            BackpressurePolicy::DropNewest => match self.sender.try_send(item) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }
+                Err(TrySendError::Disconnected(item)) => Err(SendError(item)),
+            },
+            BackpressurePolicy::DropOldest => {
+                let mut item = item;
+                loop {
+                    match self.sender.try_send(item) {
+                        Ok(()) => return Ok(()),
+                        Err(TrySendError::Full(rejected)) => {
+                            let oldest = self.oldest.as_ref().and_then(|r| r.try_recv().ok());
+                            if oldest.is_some() {
+                                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                            }
+                            item = rejected;
+                        }
+                        Err(TrySendError::Disconnected(item)) => return Err(SendError(item)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Queue depth and counters of every pipeline stage, for reporting
+#[derive(Clone, Default)]
+pub struct PipelineMonitor {
+    stages: Vec<StageMonitor>,
+}
+
+#[derive(Clone)]
+struct StageMonitor {
+    name: String,
+    capacity: Option<usize>,
+    stats: Arc<StageStats>,
+    depth: Arc<dyn Fn() -> usize + Send + Sync>,
+}
+
+impl PipelineMonitor {
+    pub fn new() -> Self {
+        PipelineMonitor::default()
+    }
+
+    /// It creates the channel feeding a pipeline stage and registers it for reporting
+    ///
+    /// Arguments:
+    ///
+    /// * `name`: Name of the stage in the reports.
+    /// * `capacity`: Maximum number of queued items, 0 for an unbounded queue.
+    /// * `policy`: What to do with new items when the queue is full.
+    ///
+    /// Returns:
+    ///
+    /// The sending and receiving halves of the stage channel
+    pub fn stage<T: Send + 'static>(
+        &mut self,
+        name: &str,
+        capacity: usize,
+        policy: BackpressurePolicy,
+    ) -> (StageSender<T>, StageReceiver<T>) {
+        let (sender, receiver) = if capacity == 0 {
+            unbounded()
+        } else {
+            bounded(capacity)
+        };
+        let stats = Arc::new(StageStats::default());
+        let depth_receiver = receiver.clone();
+        self.stages.push(StageMonitor {
+            name: name.to_string(),
+            capacity: (capacity > 0).then_some(capacity),
+            stats: stats.clone(),
+            depth: Arc::new(move || depth_receiver.len()),
+        });
+        let consumer = Arc::new(());
+        (
+            StageSender {
+                sender,
+                oldest: (policy == BackpressurePolicy::DropOldest).then(|| receiver.clone()),
+                consumer: Arc::downgrade(&consumer),
+                policy,
+                stats,
+            },
+            StageReceiver {
+                receiver,
+                _consumer: consumer,
+            },
+        )
+    }
+}
+
+impl fmt::Display for PipelineMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, stage) in self.stages.iter().enumerate() {
+            if index > 0 {
+                write!(f, ": ")?;
+            }
+            let capacity = stage
+                .capacity
+                .map_or_else(|| "unbounded".to_string(), |c| c.to_string());
+            write!(
+                f,
+                "{} depth {}/{} sent {} dropped {}",
+                stage.name,
+                (stage.depth)(),
+                capacity,
+                stage.stats.sent.load(Ordering::Relaxed),
+                stage.stats.dropped.load(Ordering::Relaxed)
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use crate::pipeline::PipelineMonitor;
+use crate::stats::ReceiverStats;
+use crate::utils::decoder_worker_index;
+use crate::BATCH_ID_SIZE;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use std::{str, thread};
+
+/// Number of source addresses listed in the report of rejected datagrams
+const REPORTED_REJECTED_SOURCES: usize = 5;
+
+/// Decode progress of a single batch that has not been reassembled yet
+#[derive(Debug, Clone)]
+pub struct BatchProgress {
+    /// Number of distinct encoding symbols received so far, as counted by the decoder of the batch
+    pub unique_symbols: usize,
+    /// Total number of packets received, duplicates included
+    pub packets_received: usize,
+    /// Number of source symbols of the batch according to its OTI, the minimum needed to decode
+    pub source_symbols: u64,
+    /// Arrival time of the first packet of the batch
+    pub first_packet: SystemTime,
+    /// Arrival time of the latest packet of the batch
+    pub last_packet: SystemTime,
+    /// Addresses that sent packets straight from the sender, with the forward flag still set
+    pub senders: HashSet<SocketAddr>,
+    /// Addresses of peers that forwarded packets of the batch
+    pub forwarders: HashSet<SocketAddr>,
+}
+
+impl BatchProgress {
+    fn new(source_symbols: u64, now: SystemTime) -> Self {
+        BatchProgress {
+            unique_symbols: 0,
+            packets_received: 0,
+            source_symbols,
+            first_packet: now,
+            last_packet: now,
+            senders: HashSet::new(),
+            forwarders: HashSet::new(),
+        }
+    }
+
+    /// Time elapsed since the latest packet of the batch arrived
+    pub fn idle(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.last_packet)
+            .unwrap_or_default()
+    }
+
+    /// Number of symbols still missing before the batch can possibly decode
+    pub fn missing_symbols(&self) -> u64 {
+        self.source_symbols
+            .saturating_sub(self.unique_symbols as u64)
+    }
+}
+
+type ProgressShard = RwLock<HashMap<[u8; BATCH_ID_SIZE], BatchProgress>>;
+
+/// Shared, queryable view of the decode progress of every in-flight batch.
+/// Cloning the tracker gives another handle on the same table, so the decoder workers record
+/// into it while operators or an embedding node query it. The table is sharded like the batches
+/// are across the decoder workers, so that a worker only ever locks its own shard.
+#[derive(Debug, Clone)]
+pub struct ProgressTracker {
+    shards: Arc<Vec<ProgressShard>>,
+}
+
+impl ProgressTracker {
+    /// Arguments:
+    ///
+    /// * `workers`: Number of decoder workers, one shard each.
+    pub fn new(workers: usize) -> Self {
+        ProgressTracker {
+            shards: Arc::new((0..workers.max(1)).map(|_| RwLock::default()).collect()),
+        }
+    }
+
+    fn shard(&self, batch_id: &[u8; BATCH_ID_SIZE]) -> &ProgressShard {
+        &self.shards[decoder_worker_index(batch_id, self.shards.len())]
+    }
+
+    /// It records the arrival of a packet for a batch
+    ///
+    /// Arguments:
+    ///
+    /// * `batch_id`: The batch id of the packet.
+    /// * `from`: The address the packet was received from.
+    /// * `forwarded`: Whether the packet was forwarded by a peer rather than sent by the sender.
+    /// * `source_symbols`: Number of source symbols of the batch.
+    /// * `unique_symbols`: Number of distinct symbols the decoder of the batch has received.
+    pub fn record(
+        &self,
+        batch_id: [u8; BATCH_ID_SIZE],
+        from: SocketAddr,
+        forwarded: bool,
+        source_symbols: u64,
+        unique_symbols: usize,
+    ) {
+        let now = SystemTime::now();
+        let mut batches = self.shard(&batch_id).write().unwrap();
+        let progress = batches
+            .entry(batch_id)
+            .or_insert_with(|| BatchProgress::new(source_symbols, now));
+        progress.last_packet = now;
+        progress.packets_received += 1;
+        if forwarded {
+            progress.forwarders.insert(from);
+        } else {
+            progress.senders.insert(from);
+        }
+        progress.unique_symbols = unique_symbols;
+    }
+
+    /// It stops tracking a batch, typically once it has been decoded
+    ///
+    /// Returns:
+    ///
+    /// The final progress of the batch, if it was tracked
+    pub fn remove(&self, batch_id: &[u8; BATCH_ID_SIZE]) -> Option<BatchProgress> {
+        self.shard(batch_id).write().unwrap().remove(batch_id)
+    }
+
+    /// Current progress of a single batch
+    #[allow(dead_code)]
+    pub fn get(&self, batch_id: &[u8; BATCH_ID_SIZE]) -> Option<BatchProgress> {
+        self.shard(batch_id).read().unwrap().get(batch_id).cloned()
+    }
+
+    /// Current progress of every in-flight batch
+    pub fn snapshot(&self) -> Vec<([u8; BATCH_ID_SIZE], BatchProgress)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                let batches = shard.read().unwrap();
+                batches
+                    .iter()
+                    .map(|(batch_id, progress)| (*batch_id, progress.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// In-flight batches that have not received a packet for at least `idle`
+    pub fn stalled(&self, idle: Duration) -> Vec<([u8; BATCH_ID_SIZE], BatchProgress)> {
+        self.snapshot()
+            .into_iter()
+            .filter(|(_, progress)| progress.idle() >= idle)
+            .collect()
+    }
+}
+
+/// It prints one line per in-flight batch every `interval`, so that batches which stop making
+/// progress are visible before they would ever decode
+///
+/// Arguments:
+///
+/// * `tracker`: The progress tracker shared with the decoder workers
+/// * `stats`: The receiver statistics, the addresses sending the most rejected datagrams and the
+///   counters of every sender, reported before the batches
+/// * `pipeline`: Queue depth and drop counts of the pipeline stages, reported before the batches
+/// * `interval`: Time between two reports
+pub fn log_progress(
+    tracker: ProgressTracker,
+    stats: Arc<ReceiverStats>,
+    pipeline: PipelineMonitor,
+    interval: Duration,
+) {
+    loop {
+        thread::sleep(interval);
+        println!("Receiver: {}", stats);
+        println!("Pipeline: {}", pipeline);
+        let rejected = stats.top_rejected_sources(REPORTED_REJECTED_SOURCES);
+        if !rejected.is_empty() {
+            let rejected: Vec<String> = rejected
+                .iter()
+                .map(|(address, count)| format!("{} {}", address, count))
+                .collect();
+            println!("Rejected datagrams by source: {}", rejected.join(": "));
+        }
+        for (namespace, sender) in stats.sender_stats() {
+            println!("Sender: {}: {}", namespace, sender);
+        }
+        let stalled: HashSet<[u8; BATCH_ID_SIZE]> = tracker
+            .stalled(interval)
+            .into_iter()
+            .map(|(batch_id, _)| batch_id)
+            .collect();
+        for (batch_id, progress) in tracker.snapshot() {
+            let state = if stalled.contains(&batch_id) {
+                "STALLED"
+            } else {
+                "receiving"
+            };
+            println!(
+                "Batch: {}: {}: symbols {}/{} (missing {}): packets {}: senders {}: forwarders {}: open for {:?}: idle for {:?}",
+                str::from_utf8(&batch_id).unwrap_or("<non-utf8>"),
+                state,
+                progress.unique_symbols,
+                progress.source_symbols,
+                progress.missing_symbols(),
+                progress.packets_received,
+                progress.senders.len(),
+                progress.forwarders.len(),
+                SystemTime::now()
+                    .duration_since(progress.first_packet)
+                    .unwrap_or_default(),
+                progress.idle()
+            );
+        }
+    }
+}
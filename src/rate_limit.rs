@@ -0,0 +1,121 @@
+use crate::NodeAddress;
+use clap::ArgEnum;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// What the forwarding rate of a peer is measured in
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateUnit {
+    Packets,
+    Bytes,
+}
+
+/// What the forwarder does with a packet once the budget of its peer is spent
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RatePolicy {
+    /// Hold the packet until the budget allows it, dropping it if too many are already held
+    Queue,
+    /// Discard the packet
+    Drop,
+}
+
+/// Budget of the packets forwarded to every peer
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// Packets or bytes per second, 0 for unlimited
+    pub rate: u64,
+    pub unit: RateUnit,
+    /// Packets or bytes that can be sent at once after an idle period, 0 for one second of rate
+    pub burst: u64,
+    pub policy: RatePolicy,
+    /// Packets held for a single peer with the queue policy
+    pub max_queued: usize,
+}
+
+/// Whether a packet may be sent to a peer
+pub enum Admission {
+    /// Within the budget
+    Now,
+    /// Over the budget, to be sent after the delay. `queued` must be decremented once it is sent.
+    Queued {
+        delay: Duration,
+        queued: Arc<AtomicUsize>,
+    },
+    /// Over the budget and discarded
+    Dropped,
+}
+
+struct TokenBucket {
+    /// Budget left, negative when packets are queued against the future budget
+    tokens: f64,
+    last_refill: Instant,
+    /// Packets queued for the peer and not sent yet
+    queued: Arc<AtomicUsize>,
+}
+
+/// A token bucket per peer, limiting how fast the forwarder sends to it
+pub struct PeerRateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<(String, u16), TokenBucket>,
+}
+
+impl PeerRateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        PeerRateLimiter {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// It takes the cost of a packet of `len` bytes from the budget of `peer`
+    pub fn admit(&mut self, peer: &NodeAddress, len: usize) -> Admission {
+        if self.limit.rate == 0 {
+            return Admission::Now;
+        }
+        let rate = self.limit.rate as f64;
+        let cost = match self.limit.unit {
+            RateUnit::Packets => 1.0,
+            RateUnit::Bytes => len as f64,
+        };
+        // A packet costlier than the burst would never fit in the bucket
+        let capacity = match self.limit.burst {
+            0 => rate,
+            burst => burst as f64,
+        }
+        .max(cost);
+        let bucket = self
+            .buckets
+            .entry((peer.ip_addr.clone(), peer.port))
+            .or_insert_with(|| TokenBucket {
+                tokens: capacity,
+                last_refill: Instant::now(),
+                queued: Arc::new(AtomicUsize::new(0)),
+            });
+        let now = Instant::now();
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.last_refill).as_secs_f64() * rate)
+            .min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            return Admission::Now;
+        }
+        match self.limit.policy {
+            RatePolicy::Drop => Admission::Dropped,
+            RatePolicy::Queue if bucket.queued.load(Ordering::Relaxed) >= self.limit.max_queued => {
+                Admission::Dropped
+            }
+            RatePolicy::Queue => {
+                bucket.tokens -= cost;
+                bucket.queued.fetch_add(1, Ordering::Relaxed);
+                Admission::Queued {
+                    delay: Duration::from_secs_f64(-bucket.tokens / rate),
+                    queued: bucket.queued.clone(),
+                }
+            }
+        }
+    }
+}
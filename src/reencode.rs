@@ -0,0 +1,146 @@
+use crate::discovery::Membership;
+use crate::have::PeerHaves;
+use crate::identity::SelfIdentity;
+use crate::pipeline::{StageReceiver, StageSender};
+use crate::retransmit::{Forward, FLAG_FORWARDED};
+use crate::stats::ReceiverStats;
+use crate::utils::{batch_name, create_packet, decoder_config};
+use crate::{NodeAddress, NodeType, BATCH_ID_SIZE};
+use raptorq::Encoder;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// First repair symbol id minted by the receivers, far above the repair symbols of the sender
+const FIRST_REENCODED_REPAIR_ID: u32 = 1 << 20;
+
+/// Repair symbol ids reserved for every receiver. Small ranges leave room for thousands of
+/// receivers, `--reencode-symbols` caps what a receiver mints from its range
+pub const REPAIR_IDS_PER_NODE: u32 = 1 << 12;
+
+/// Number of repair ranges, as encoding symbol ids are 24 bits long
+pub const NODES_WITH_REPAIR_RANGE: u32 =
+    ((1 << 24) - FIRST_REENCODED_REPAIR_ID) / REPAIR_IDS_PER_NODE - 1;
+
+/// Index of the repair range of a receiver. With a node list every node knows the same receivers,
+/// so the range follows the position of the receiver among them and no two receivers share one.
+/// Discovered nodes differ in the receivers they know of, so the range is picked by a hash of the
+/// name of the receiver instead, and two receivers hashing to the same range mint the same symbols.
+///
+/// Arguments:
+///
+/// * `node`: The receiver whose range is wanted.
+/// * `receivers`: The known receivers, in the same order on every node.
+/// * `fixed`: Whether the receivers come from the node list.
+fn repair_range(node: &NodeAddress, receivers: &[NodeAddress], fixed: bool) -> u32 {
+    let position = fixed
+        .then(|| receivers.iter().position(|receiver| receiver == node))
+        .flatten();
+    match position {
+        Some(position) => position as u32 % NODES_WITH_REPAIR_RANGE,
+        None => {
+            let digest = Sha256::digest(node.name().as_bytes());
+            let hash = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+            hash % NODES_WITH_REPAIR_RANGE
+        }
+    }
+}
+
+/// It re-encodes the payload of every batch this node decoded with the OTI of the sender, and
+/// hands `symbols` repair symbols from the range of this node to the forwarder, which sends them to
+/// the peers that have not decoded the batch yet. The symbols come from the range of this node, so
+/// they are new to the peers instead of duplicates of what the sender or other re-encoding peers
+/// sent, unless another known receiver has the same range, which is logged once per receiver.
+/// Batches every peer has announced are not re-encoded. It returns once the decoder workers are
+/// gone.
+///
+/// Arguments:
+///
+/// * `receiver`: The id and payload of every decoded batch.
+/// * `forwarder`: The forwarder channel the repair packets are handed to.
+/// * `membership`: The live nodes, among which this node finds its own entry.
+/// * `identity`: How this node recognizes itself among the nodes.
+/// * `haves`: The peers known to have decoded every recent batch.
+/// * `symbols`: Number of repair symbols minted per batch and source block.
+/// * `range`: The repair range set for this node, otherwise it is derived from the receivers.
+/// * `stats`: Receiver statistics shared with the rest of the pipeline
+#[allow(clippy::too_many_arguments)]
+pub fn reencode_handler(
+    receiver: StageReceiver<([u8; BATCH_ID_SIZE], Vec<u8>)>,
+    forwarder: StageSender<Forward>,
+    membership: Arc<Membership>,
+    identity: SelfIdentity,
+    haves: Arc<PeerHaves>,
+    symbols: u32,
+    range: Option<u32>,
+    stats: Arc<ReceiverStats>,
+) {
+    let config = decoder_config();
+    let mut collisions = HashSet::new();
+    while let Ok((batch_id, payload)) = receiver.recv() {
+        let nodes = membership.live_nodes();
+        // Observers get the minted symbols as well
+        if nodes
+            .iter()
+            .filter(|node| node.role.decodes())
+            .all(|node| identity.is(node) || haves.has(&batch_id, node))
+        {
+            continue;
+        }
+        // Without its own entry this node cannot tell its range, and could mint the symbols of
+        // another receiver
+        let Some(own) = nodes
+            .iter()
+            .find(|node| node.role == NodeType::Receiver && identity.is(node))
+        else {
+            println!(
+                "Batch: {}: Not re-encoded: this node is not among the live receivers",
+                batch_name(&batch_id)
+            );
+            continue;
+        };
+        let receivers: Vec<NodeAddress> = membership
+            .known_nodes()
+            .into_iter()
+            .filter(|node| node.role == NodeType::Receiver)
+            .collect();
+        let fixed = membership.is_fixed();
+        let own_range = range.unwrap_or_else(|| repair_range(own, &receivers, fixed));
+        for receiver in &receivers {
+            if receiver != own
+                && repair_range(receiver, &receivers, fixed) == own_range
+                && collisions.insert(receiver.name())
+            {
+                println!(
+                    "WARNING: receiver {} has the repair range {} of this node, both re-encode the same symbols. Set --repair-range on one of them.",
+                    receiver.name(),
+                    own_range
+                );
+            }
+        }
+        let first_repair_id = FIRST_REENCODED_REPAIR_ID + own_range * REPAIR_IDS_PER_NODE;
+        let started = Instant::now();
+        let encoder = Encoder::new(&payload, config);
+        let mut minted = 0;
+        for block in encoder.get_block_encoders() {
+            // Repair symbol ids are counted past the source symbols of the block, as the sender does
+            for packet in block.repair_packets(first_repair_id, symbols.min(REPAIR_IDS_PER_NODE)) {
+                let mut packet = create_packet(batch_id, &packet.serialize());
+                packet[1] = FLAG_FORWARDED;
+                if forwarder.send(Forward::Repair(packet)).is_err() {
+                    return;
+                }
+                minted += 1;
+            }
+        }
+        ReceiverStats::add(&stats.symbols_reencoded, minted);
+        println!(
+            "Batch: {}: Re-encoded {} repair symbols from id {} in {:?}",
+            batch_name(&batch_id),
+            minted,
+            first_repair_id,
+            started.elapsed()
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use crate::identity::SelfIdentity;
+use crate::utils::{get_batch_id, get_payload_id};
+use crate::{NodeAddress, NodeType, BATCH_ID_SIZE};
+use clap::ArgEnum;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use sha2::{Digest, Sha256};
+
+/// Forward flag of a packet sent straight from the sender
+pub const FLAG_DIRECT: u8 = 1;
+/// Forward flag of a packet forwarded by a peer, which must not be forwarded again
+pub const FLAG_FORWARDED: u8 = 0;
+/// Forward flag of a packet relayed down a retransmit tree, which the receiving node forwards to
+/// its own children
+pub const FLAG_RELAYED: u8 = 2;
+
+/// What the decoder workers and the re-encoder hand to the forwarder
+pub enum Forward {
+    /// A packet received from the network, passed on along the retransmit topology
+    Packet(Vec<u8>),
+    /// A batch this node decoded, announced to every peer with a have batch notice
+    Have([u8; BATCH_ID_SIZE]),
+    /// A repair symbol minted by this node, sent to every peer that has not decoded its batch
+    Repair(Vec<u8>),
+}
+
+/// How receivers retransmit the packets they get
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetransmitMode {
+    /// Every packet received from the sender is forwarded to every other receiver
+    AllToAll,
+    /// Every packet travels down a tree of receivers, each node forwarding only to its children
+    Tree,
+}
+
+/// How the sender spreads the packets of a batch over the receivers
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardAssignment {
+    /// Every receiver gets an equal share
+    RoundRobin,
+    /// Every receiver gets a share proportional to its weight, and in tree mode heavier receivers
+    /// sit closer to the roots and retransmit more
+    Weighted,
+}
+
+/// How packets flow between the receivers, which the sender and every receiver must agree on
+#[derive(Debug, Clone, Copy)]
+pub struct RetransmitConfig {
+    pub mode: RetransmitMode,
+    pub assignment: ShardAssignment,
+    /// Number of children of every node of a retransmit tree
+    pub fanout: usize,
+}
+
+/// The receivers of the cluster and how packets flow between them
+pub struct Topology {
+    /// The receivers and relays, in the same order on every node
+    nodes: Vec<NodeAddress>,
+    /// The observers but this node, sent every packet by the node the sender handed it to
+    observers: Vec<NodeAddress>,
+    /// Whether this node is an observer, which never forwards
+    observing: bool,
+    mode: RetransmitMode,
+    assignment: ShardAssignment,
+    /// Number of children of every node of a retransmit tree
+    fanout: usize,
+    /// Position of this node in `nodes`, `None` on the sender
+    self_index: Option<usize>,
+}
+
+impl Topology {
+    /// Arguments:
+    ///
+    /// * `nodes`: The cluster nodes, only the receivers and relays take part in the retransmission.
+    /// * `config`: How packets are spread over the receivers and retransmitted.
+    /// * `identity`: How this node recognizes itself among the nodes, `None` on the sender.
+    pub fn new(
+        nodes: Vec<NodeAddress>,
+        config: RetransmitConfig,
+        identity: Option<&SelfIdentity>,
+    ) -> Self {
+        let (observers, nodes): (Vec<NodeAddress>, Vec<NodeAddress>) = nodes
+            .into_iter()
+            .filter(|n| n.role != NodeType::Sender)
+            .partition(|n| n.role == NodeType::Observer);
+        let is_self = |node: &NodeAddress| identity.is_some_and(|identity| identity.is(node));
+        let observing = observers.iter().any(is_self);
+        let observers = observers.into_iter().filter(|n| !is_self(n)).collect();
+        let self_index = identity.and_then(|identity| nodes.iter().position(|n| identity.is(n)));
+        // Without any weight there is nothing to be proportional to
+        let assignment = if nodes.iter().all(|n| n.weight == 0) {
+            ShardAssignment::RoundRobin
+        } else {
+            config.assignment
+        };
+        Topology {
+            nodes,
+            observers,
+            observing,
+            mode: config.mode,
+            assignment,
+            fanout: config.fanout.max(1),
+            self_index,
+        }
+    }
+
+    pub fn nodes(&self) -> &[NodeAddress] {
+        &self.nodes
+    }
+
+    /// The observers but this node
+    pub fn observers(&self) -> &[NodeAddress] {
+        &self.observers
+    }
+
+    /// The receiver the sender hands a packet to. In tree mode it is the root of the packet tree.
+    ///
+    /// Arguments:
+    ///
+    /// * `packet_index`: Position of the packet in the transmission.
+    /// * `packet`: The packet, header included.
+    pub fn destination(&self, packet_index: usize, packet: &[u8]) -> &NodeAddress {
+        let batch_id = get_batch_id(packet);
+        match (self.mode, self.assignment) {
+            (RetransmitMode::AllToAll, ShardAssignment::RoundRobin) => {
+                &self.nodes[packet_index % self.nodes.len()]
+            }
+            (RetransmitMode::AllToAll, ShardAssignment::Weighted) => {
+                &self.nodes[self.weighted_pick(&batch_id, packet)]
+            }
+            (RetransmitMode::Tree, _) => {
+                let order = self.tree_order(&batch_id);
+                &self.nodes[order[self.root_position(&batch_id, &order, packet)]]
+            }
+        }
+    }
+
+    /// The nodes this node forwards a received packet to. The node the sender handed the packet
+    /// to also forwards it to every observer, and observers forward nothing.
+    pub fn targets(&self, packet: &[u8]) -> Vec<&NodeAddress> {
+        if self.observing {
+            return vec![];
+        }
+        let mut targets = self.retransmit_targets(packet);
+        if packet[1] == FLAG_DIRECT {
+            targets.extend(&self.observers);
+        }
+        targets
+    }
+
+    /// The receivers and relays this node forwards a received packet to
+    fn retransmit_targets(&self, packet: &[u8]) -> Vec<&NodeAddress> {
+        match self.mode {
+            RetransmitMode::AllToAll if packet[1] == FLAG_DIRECT => self.peers().collect(),
+            RetransmitMode::AllToAll => vec![],
+            RetransmitMode::Tree => {
+                let Some(self_index) = self.self_index else {
+                    return vec![];
+                };
+                let n = self.nodes.len();
+                let batch_id = get_batch_id(packet);
+                let order = self.tree_order(&batch_id);
+                let root = self.root_position(&batch_id, &order, packet);
+                let Some(position) = tree_position(&order, root, self_index) else {
+                    return vec![];
+                };
+                (position * self.fanout + 1..=position * self.fanout + self.fanout)
+                    .take_while(|child| *child < n)
+                    .map(|child| &self.nodes[order[(root + child) % n]])
+                    .collect()
+            }
+        }
+    }
+
+    /// Whether `node` forwards the packet further once it receives it from this node, in which
+    /// case it must get the packet even if it has already decoded the batch
+    pub fn relays(&self, node: &NodeAddress, packet: &[u8]) -> bool {
+        match self.mode {
+            RetransmitMode::AllToAll => false,
+            RetransmitMode::Tree => {
+                let Some(index) = self
+                    .nodes
+                    .iter()
+                    .position(|n| n.ip_addr == node.ip_addr && n.port == node.port)
+                else {
+                    return false;
+                };
+                let batch_id = get_batch_id(packet);
+                let order = self.tree_order(&batch_id);
+                let root = self.root_position(&batch_id, &order, packet);
+                tree_position(&order, root, index)
+                    .is_some_and(|position| position * self.fanout + 1 < self.nodes.len())
+            }
+        }
+    }
+
+    /// Every receiver and relay but this node
+    pub fn peers(&self) -> impl Iterator<Item = &NodeAddress> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(move |(index, _)| Some(*index) != self.self_index)
+            .map(|(_, node)| node)
+    }
+
+    /// The forward flag set on the packets this node forwards
+    pub fn forward_flag(&self) -> u8 {
+        match self.mode {
+            RetransmitMode::AllToAll => FLAG_FORWARDED,
+            RetransmitMode::Tree => FLAG_RELAYED,
+        }
+    }
+
+    /// The receivers shuffled deterministically by batch id, so every node builds the same trees.
+    /// With weighted assignment heavier receivers tend to come first, closer to the roots.
+    fn tree_order(&self, batch_id: &[u8; BATCH_ID_SIZE]) -> Vec<usize> {
+        let mut rng = StdRng::from_seed(batch_seed(batch_id));
+        let mut order: Vec<usize> = (0..self.nodes.len()).collect();
+        match self.assignment {
+            ShardAssignment::RoundRobin => order.shuffle(&mut rng),
+            ShardAssignment::Weighted => {
+                // Weighted random sampling without replacement: every node draws u^(1 / weight)
+                // and the highest draws go first, nodes without weight last
+                let keys: Vec<f64> = self
+                    .nodes
+                    .iter()
+                    .map(|n| match n.weight {
+                        0 => -1.0,
+                        weight => rng.gen::<f64>().powf(1.0 / weight as f64),
+                    })
+                    .collect();
+                order.sort_by(|a, b| keys[*b].total_cmp(&keys[*a]));
+            }
+        }
+        order
+    }
+
+    /// Position in the shuffled order of the root of the packet tree. Every encoding symbol gets
+    /// its own tree, so the load of the root and of the inner nodes is spread over the receivers.
+    fn root_position(
+        &self,
+        batch_id: &[u8; BATCH_ID_SIZE],
+        order: &[usize],
+        packet: &[u8],
+    ) -> usize {
+        match self.assignment {
+            ShardAssignment::RoundRobin => {
+                get_payload_id(packet).encoding_symbol_id() as usize % self.nodes.len()
+            }
+            ShardAssignment::Weighted => {
+                let root = self.weighted_pick(batch_id, packet);
+                order.iter().position(|node| *node == root).unwrap_or(0)
+            }
+        }
+    }
+
+    /// A receiver picked with a probability proportional to its weight, from the batch id and the
+    /// payload id of the packet so that it is the same on every node
+    fn weighted_pick(&self, batch_id: &[u8; BATCH_ID_SIZE], packet: &[u8]) -> usize {
+        let total: u64 = self.nodes.iter().map(|n| n.weight).sum();
+        let seed = batch_seed(batch_id);
+        let symbol = u32::from_be_bytes(get_payload_id(packet).serialize()) as u64;
+        let mut point =
+            mix(u64::from_be_bytes(seed[..8].try_into().unwrap_or_default()) ^ symbol) % total;
+        for (index, node) in self.nodes.iter().enumerate() {
+            if point < node.weight {
+                return index;
+            }
+            point -= node.weight;
+        }
+        self.nodes.len() - 1
+    }
+}
+
+/// Position of a node in the packet tree, counted from its root
+fn tree_position(order: &[usize], root: usize, node: usize) -> Option<usize> {
+    let n = order.len();
+    order
+        .iter()
+        .position(|other| *other == node)
+        .map(|position| (position + n - root) % n)
+}
+
+/// Seed of the per batch random choices, the same on every node
+fn batch_seed(batch_id: &[u8; BATCH_ID_SIZE]) -> [u8; 32] {
+    Sha256::digest(batch_id).into()
+}
+
+/// It scrambles a 64 bit value into a uniformly distributed one (SplitMix64 finalizer)
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
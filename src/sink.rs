@@ -0,0 +1,273 @@
+use clap::ArgEnum;
+use nix::unistd::dup2;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A reassembled batch along with what is known about how it was received
+#[derive(Debug, Clone)]
+pub struct DecodedBatch {
+    /// The batch id
+    pub batch_id: String,
+    /// The decoded payload
+    pub contents: Vec<u8>,
+    /// Number of unique symbols the decoder needed
+    pub num_packets: usize,
+    /// Number of packets received for the batch, duplicates included
+    pub packets_received: usize,
+    /// Number of addresses that sent packets straight from the sender
+    pub senders: usize,
+    /// Number of peers that forwarded packets of the batch
+    pub forwarders: usize,
+    /// Time between the first packet of the batch and its decoding
+    pub decode_time: Duration,
+    /// When the batch was decoded
+    pub decoded_at: SystemTime,
+}
+
+/// Destination of the batches the receiver decodes
+pub trait OutputSink: Send {
+    /// It hands a decoded batch to the sink
+    fn write_batch(&mut self, batch: &DecodedBatch) -> io::Result<()>;
+
+    /// It makes sure every batch written so far has reached its destination
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Kind of output sink selected on the command line
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    /// One `<batch_id>.BATCH` file per batch in a directory
+    Dir,
+    /// Framed batches written to the standard output, the logs going to the standard error
+    Stdout,
+    /// Framed batches written to a named pipe
+    Pipe,
+}
+
+/// It writes every batch to `<batch_id>.BATCH` in a directory. Each batch is written to a
+/// temporary file, synced to disk and then renamed, so a crash never leaves a truncated `.BATCH`
+/// file behind. With `sidecar` set, a `<batch_id>.BATCH.meta` file recording the size and SHA-256
+/// of the batch is written the same way once the batch itself is in place.
+pub struct DirectorySink {
+    dir: PathBuf,
+    sidecar: bool,
+}
+
+/// Contents of the sidecar file written next to every batch
+#[derive(Debug, Serialize)]
+struct BatchSidecar<'a> {
+    batch_id: &'a str,
+    size: usize,
+    sha256: String,
+    num_packets: usize,
+    decoded_at_unix_ms: u128,
+}
+
+impl DirectorySink {
+    /// It creates the output directory if it does not exist yet
+    pub fn new(dir: PathBuf, sidecar: bool) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(DirectorySink { dir, sidecar })
+    }
+
+    /// It atomically replaces `file_name` in the output directory with `contents`
+    fn write_atomically(&self, file_name: &str, contents: &[u8]) -> io::Result<()> {
+        let tmp_path = self.dir.join(format!(".{}.tmp", file_name));
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(contents)?;
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(file_name))?;
+        // The rename itself only survives a crash once the directory entry is on disk
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+impl OutputSink for DirectorySink {
+    fn write_batch(&mut self, batch: &DecodedBatch) -> io::Result<()> {
+        let batch_fname = format!("{}.BATCH", batch.batch_id);
+        self.write_atomically(&batch_fname, &batch.contents)?;
+
+        if self.sidecar {
+            let sidecar = BatchSidecar {
+                batch_id: &batch.batch_id,
+                size: batch.contents.len(),
+                sha256: Sha256::digest(&batch.contents)
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect(),
+                num_packets: batch.num_packets,
+                decoded_at_unix_ms: batch
+                    .decoded_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis(),
+            };
+            let contents = serde_yaml::to_string(&sidecar).map_err(io::Error::other)?;
+            self.write_atomically(&format!("{}.meta", batch_fname), contents.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// It writes every batch to a byte stream, such as the standard output or a named pipe.
+/// Each batch is framed by a header line so a reader can split them and see how it was received:
+/// `<batch_id> <length> <num_packets> <packets_received> <senders> <forwarders> <decode_time_ms>
+/// <decoded_at_unix_ms>\n`, followed by `length` bytes of payload.
+pub struct StreamSink<W: Write + Send> {
+    writer: W,
+}
+
+impl StreamSink<File> {
+    /// It takes the standard output over for the batches. Everything printed from then on, the
+    /// logs, goes to the standard error instead, so it never ends up inside a batch.
+    pub fn stdout() -> io::Result<Self> {
+        let stdout = io::stdout();
+        stdout.lock().flush()?;
+        let writer = File::from(stdout.as_fd().try_clone_to_owned()?);
+        dup2(io::stderr().as_raw_fd(), stdout.as_raw_fd()).map_err(io::Error::from)?;
+        Ok(StreamSink { writer })
+    }
+
+    /// It opens an existing named pipe, or any other file, for writing. Opening a pipe blocks until
+    /// a reader opens the other end.
+    pub fn pipe(path: PathBuf) -> io::Result<Self> {
+        let writer = OpenOptions::new().append(true).open(path)?;
+        Ok(StreamSink { writer })
+    }
+}
+
+impl<W: Write + Send> OutputSink for StreamSink<W> {
+    fn write_batch(&mut self, batch: &DecodedBatch) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "{} {} {} {} {} {} {} {}",
+            batch.batch_id,
+            batch.contents.len(),
+            batch.num_packets,
+            batch.packets_received,
+            batch.senders,
+            batch.forwarders,
+            batch.decode_time.as_millis(),
+            batch
+                .decoded_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis()
+        )?;
+        self.writer.write_all(&batch.contents)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// It keeps every batch in memory, for tests and for nodes embedding the receiver
+#[allow(dead_code)]
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    batches: Arc<Mutex<Vec<DecodedBatch>>>,
+}
+
+#[allow(dead_code)]
+impl MemorySink {
+    pub fn new() -> Self {
+        MemorySink::default()
+    }
+
+    /// The batches collected so far, shared with every clone of the sink
+    pub fn batches(&self) -> Vec<DecodedBatch> {
+        self.batches.lock().unwrap().clone()
+    }
+}
+
+impl OutputSink for MemorySink {
+    fn write_batch(&mut self, batch: &DecodedBatch) -> io::Result<()> {
+        self.batches.lock().unwrap().push(batch.clone());
+        Ok(())
+    }
+}
+
+/// It hands every batch to a user supplied function, so an embedding node can consume the decoded
+/// transactions directly
+#[allow(dead_code)]
+pub struct CallbackSink<F: FnMut(&DecodedBatch) -> io::Result<()> + Send> {
+    callback: F,
+}
+
+#[allow(dead_code)]
+impl<F: FnMut(&DecodedBatch) -> io::Result<()> + Send> CallbackSink<F> {
+    pub fn new(callback: F) -> Self {
+        CallbackSink { callback }
+    }
+}
+
+impl<F: FnMut(&DecodedBatch) -> io::Result<()> + Send> OutputSink for CallbackSink<F> {
+    fn write_batch(&mut self, batch: &DecodedBatch) -> io::Result<()> {
+        (self.callback)(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{BackpressurePolicy, PipelineMonitor};
+    use crate::stats::ReceiverStats;
+    use crate::utils::file_writer;
+
+    fn batch(batch_id: &str, contents: &[u8]) -> DecodedBatch {
+        DecodedBatch {
+            batch_id: batch_id.to_string(),
+            contents: contents.to_vec(),
+            num_packets: 1,
+            packets_received: 1,
+            senders: 1,
+            forwarders: 0,
+            decode_time: Duration::from_millis(1),
+            decoded_at: SystemTime::now(),
+        }
+    }
+
+    /// It runs the batches through the writer stage into `sink`, until the stage is drained
+    fn write_through(batches: Vec<DecodedBatch>, sink: Box<dyn OutputSink>) {
+        let (send, receive) = PipelineMonitor::new().stage("writer", 0, BackpressurePolicy::Block);
+        for batch in batches {
+            send.send(batch).unwrap();
+        }
+        drop(send);
+        file_writer(receive, sink, Arc::new(ReceiverStats::new()));
+    }
+
+    #[test]
+    fn memory_sink_collects_the_written_batches() {
+        let sink = MemorySink::new();
+        write_through(
+            vec![batch("first", b"one"), batch("second", b"two")],
+            Box::new(sink.clone()),
+        );
+        let batches = sink.batches();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].batch_id, "first");
+        assert_eq!(batches[1].contents, b"two");
+    }
+
+    #[test]
+    fn callback_sink_hands_every_batch_to_the_callback() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let callback_seen = seen.clone();
+        let sink = CallbackSink::new(move |batch: &DecodedBatch| {
+            callback_seen.lock().unwrap().push(batch.batch_id.clone());
+            Ok(())
+        });
+        write_through(vec![batch("only", b"payload")], Box::new(sink));
+        assert_eq!(*seen.lock().unwrap(), vec!["only".to_string()]);
+    }
+}
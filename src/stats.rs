@@ -0,0 +1,273 @@
+use crate::error::Error;
+use crate::namespace::SenderNamespace;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Maximum number of source addresses whose rejected datagrams are counted separately, so that
+/// spoofed sources cannot grow the table without bound
+const MAX_REJECTED_SOURCES: usize = 1024;
+
+/// Maximum number of senders counted separately
+const MAX_TRACKED_SENDERS: usize = 1024;
+
+/// Counters of the batches of a single sender
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct SenderStats {
+    pub packets_received: u64,
+    pub batches_decoded: u64,
+    pub bytes_decoded: u64,
+    pub batches_evicted: u64,
+}
+
+/// Counters describing what the receiver pipeline has done so far, shared by all its threads
+#[derive(Debug, Default)]
+pub struct ReceiverStats {
+    /// Packets handed to the decoder workers
+    pub packets_received: AtomicU64,
+    /// Data packets dropped on purpose with --simulated-loss, before reaching the decoder workers
+    pub packets_lost_simulated: AtomicU64,
+    /// Bytes of the packets handed to the decoder workers
+    pub bytes_received: AtomicU64,
+    /// Batches successfully reassembled
+    pub batches_decoded: AtomicU64,
+    /// Bytes of the reassembled batches
+    pub bytes_decoded: AtomicU64,
+    /// Packets sent to peers by the forwarder
+    pub packets_forwarded: AtomicU64,
+    /// Bytes sent to peers by the forwarder
+    pub bytes_forwarded: AtomicU64,
+    /// Packets not forwarded to peers that announced they had decoded the batch
+    pub packets_suppressed: AtomicU64,
+    /// Have batch notices sent to peers
+    pub have_notices_sent: AtomicU64,
+    /// Repair symbols minted from the payload of decoded batches
+    pub symbols_reencoded: AtomicU64,
+    /// Minted repair symbols sent to peers that had not decoded their batch
+    pub reencoded_sent: AtomicU64,
+    /// Forwarded packets held back by the rate limit of their peer
+    pub packets_rate_queued: AtomicU64,
+    /// Packets not forwarded because the rate limit of their peer was exceeded
+    pub packets_rate_dropped: AtomicU64,
+    /// In-flight batches whose decoder was dropped before they could decode
+    pub batches_evicted: AtomicU64,
+    /// Failed socket, file or pipe operations
+    pub io_errors: AtomicU64,
+    /// Datagrams dropped because they do not follow the packet layout
+    pub malformed_packets: AtomicU64,
+    /// Packets dropped because the decoder of their batch cannot use them
+    pub decode_errors: AtomicU64,
+    /// Options rejected at run time
+    pub config_errors: AtomicU64,
+    /// Malformed or undecodable datagrams per source address
+    rejected_sources: Mutex<HashMap<IpAddr, u64>>,
+    /// Counters per sender namespace
+    senders: Mutex<HashMap<SenderNamespace, SenderStats>>,
+}
+
+impl ReceiverStats {
+    pub fn new() -> Self {
+        ReceiverStats::default()
+    }
+
+    /// It adds one to a counter
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// It adds `value` to a counter
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// It counts an error under its kind
+    pub fn record_error(&self, error: &Error) {
+        ReceiverStats::increment(match error {
+            Error::Io(_) => &self.io_errors,
+            Error::MalformedPacket(_) => &self.malformed_packets,
+            Error::Decode(_) => &self.decode_errors,
+            Error::Config(_) => &self.config_errors,
+        });
+    }
+
+    /// It counts a datagram dropped by the packet validation, under its error kind and under its
+    /// source address
+    ///
+    /// Returns:
+    ///
+    /// Whether the address started being tracked with this datagram
+    pub fn record_rejection(&self, from: SocketAddr, error: &Error) -> bool {
+        self.record_error(error);
+        let mut sources = self.rejected_sources.lock().unwrap();
+        if let Some(count) = sources.get_mut(&from.ip()) {
+            *count += 1;
+            return false;
+        }
+        if sources.len() >= MAX_REJECTED_SOURCES {
+            return false;
+        }
+        sources.insert(from.ip(), 1);
+        true
+    }
+
+    /// It updates the counters of a sender, unless too many senders are tracked already
+    pub fn record_sender(&self, namespace: SenderNamespace, update: impl FnOnce(&mut SenderStats)) {
+        let mut senders = self.senders.lock().unwrap();
+        if !senders.contains_key(&namespace) && senders.len() >= MAX_TRACKED_SENDERS {
+            return;
+        }
+        update(senders.entry(namespace).or_default());
+    }
+
+    /// The counters of every sender, ordered by namespace
+    pub fn sender_stats(&self) -> Vec<(SenderNamespace, SenderStats)> {
+        let mut senders: Vec<(SenderNamespace, SenderStats)> = self
+            .senders
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(namespace, stats)| (*namespace, *stats))
+            .collect();
+        senders.sort_by_key(|(namespace, _)| *namespace);
+        senders
+    }
+
+    /// The `n` source addresses with the most rejected datagrams, most first
+    pub fn top_rejected_sources(&self, n: usize) -> Vec<(IpAddr, u64)> {
+        let mut sources: Vec<(IpAddr, u64)> = self
+            .rejected_sources
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(address, count)| (*address, *count))
+            .collect();
+        sources.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        sources.truncate(n);
+        sources
+    }
+}
+
+/// What the receiver did over its whole run, reported when it shuts down
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub batches_completed: u64,
+    /// Batches still being decoded when the receiver stopped
+    pub batches_incomplete: usize,
+    pub batches_evicted: u64,
+    pub packets_received: u64,
+    pub packets_lost_simulated: u64,
+    pub bytes_received: u64,
+    pub bytes_decoded: u64,
+    pub packets_forwarded: u64,
+    pub bytes_forwarded: u64,
+    /// Forwards saved by the have batch notices of the peers
+    pub packets_suppressed: u64,
+    pub have_notices_sent: u64,
+    pub symbols_reencoded: u64,
+    pub reencoded_sent: u64,
+    pub packets_rate_queued: u64,
+    pub packets_rate_dropped: u64,
+    pub io_errors: u64,
+    pub malformed_packets: u64,
+    pub decode_errors: u64,
+    /// Rejected datagrams per source address
+    pub rejected_sources: BTreeMap<String, u64>,
+    /// Counters per sender namespace
+    pub senders: BTreeMap<String, SenderStats>,
+}
+
+impl RunSummary {
+    pub fn new(stats: &ReceiverStats, batches_incomplete: usize) -> Self {
+        RunSummary {
+            batches_completed: stats.batches_decoded.load(Ordering::Relaxed),
+            batches_incomplete,
+            batches_evicted: stats.batches_evicted.load(Ordering::Relaxed),
+            packets_received: stats.packets_received.load(Ordering::Relaxed),
+            packets_lost_simulated: stats.packets_lost_simulated.load(Ordering::Relaxed),
+            bytes_received: stats.bytes_received.load(Ordering::Relaxed),
+            bytes_decoded: stats.bytes_decoded.load(Ordering::Relaxed),
+            packets_forwarded: stats.packets_forwarded.load(Ordering::Relaxed),
+            bytes_forwarded: stats.bytes_forwarded.load(Ordering::Relaxed),
+            packets_suppressed: stats.packets_suppressed.load(Ordering::Relaxed),
+            have_notices_sent: stats.have_notices_sent.load(Ordering::Relaxed),
+            symbols_reencoded: stats.symbols_reencoded.load(Ordering::Relaxed),
+            reencoded_sent: stats.reencoded_sent.load(Ordering::Relaxed),
+            packets_rate_queued: stats.packets_rate_queued.load(Ordering::Relaxed),
+            packets_rate_dropped: stats.packets_rate_dropped.load(Ordering::Relaxed),
+            io_errors: stats.io_errors.load(Ordering::Relaxed),
+            malformed_packets: stats.malformed_packets.load(Ordering::Relaxed),
+            decode_errors: stats.decode_errors.load(Ordering::Relaxed),
+            rejected_sources: stats
+                .top_rejected_sources(MAX_REJECTED_SOURCES)
+                .into_iter()
+                .map(|(address, count)| (address.to_string(), count))
+                .collect(),
+            senders: stats
+                .sender_stats()
+                .into_iter()
+                .map(|(namespace, stats)| (namespace.to_string(), stats))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Batches completed: {}: Batches incomplete: {}: Batches evicted: {}: Packets received: {}: Packets lost on purpose: {}: Bytes received: {}: Bytes decoded: {}: Packets forwarded: {}: Bytes forwarded: {}: Packets suppressed: {}: Have notices sent: {}: Symbols re-encoded: {}: Re-encoded symbols sent: {}: Rate limited: queued {} dropped {}: I/O errors: {}: Malformed packets: {}: Decode errors: {}",
+            self.batches_completed,
+            self.batches_incomplete,
+            self.batches_evicted,
+            self.packets_received,
+            self.packets_lost_simulated,
+            self.bytes_received,
+            self.bytes_decoded,
+            self.packets_forwarded,
+            self.bytes_forwarded,
+            self.packets_suppressed,
+            self.have_notices_sent,
+            self.symbols_reencoded,
+            self.reencoded_sent,
+            self.packets_rate_queued,
+            self.packets_rate_dropped,
+            self.io_errors,
+            self.malformed_packets,
+            self.decode_errors
+        )
+    }
+}
+
+impl fmt::Display for ReceiverStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Packets received: {}: Packets forwarded: {}: Packets suppressed: {}: Re-encoded symbols sent: {}: Rate limited: queued {} dropped {}: Batches decoded: {}: Batches evicted: {}: Errors: io {} malformed {} decode {} config {}",
+            self.packets_received.load(Ordering::Relaxed),
+            self.packets_forwarded.load(Ordering::Relaxed),
+            self.packets_suppressed.load(Ordering::Relaxed),
+            self.reencoded_sent.load(Ordering::Relaxed),
+            self.packets_rate_queued.load(Ordering::Relaxed),
+            self.packets_rate_dropped.load(Ordering::Relaxed),
+            self.batches_decoded.load(Ordering::Relaxed),
+            self.batches_evicted.load(Ordering::Relaxed),
+            self.io_errors.load(Ordering::Relaxed),
+            self.malformed_packets.load(Ordering::Relaxed),
+            self.decode_errors.load(Ordering::Relaxed),
+            self.config_errors.load(Ordering::Relaxed)
+        )
+    }
+}
+
+impl fmt::Display for SenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Packets received: {}: Batches decoded: {}: Bytes decoded: {}: Batches evicted: {}",
+            self.packets_received, self.batches_decoded, self.bytes_decoded, self.batches_evicted
+        )
+    }
+}
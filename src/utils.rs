@@ -1,13 +1,26 @@
-use crate::BATCH_ID_SIZE;
-use crossbeam_channel::{Receiver, Sender};
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng, RngCore};
-use raptorq::{Decoder, Encoder, EncodingPacket, ObjectTransmissionInformation};
-use std::collections::{HashMap, HashSet};
+use crate::ack::AckSender;
+use crate::completed_batches::CompletedBatches;
+use crate::decoder_table::{Eviction, SenderDecoders};
+use crate::error::Error;
+use crate::namespace::SenderNamespace;
+use crate::pipeline::{StageReceiver, StageSender};
+use crate::progress::{BatchProgress, ProgressTracker};
+use crate::retransmit::{Forward, FLAG_DIRECT, FLAG_FORWARDED, FLAG_RELAYED};
+use crate::sink::{DecodedBatch, OutputSink};
+use crate::stats::ReceiverStats;
+use crate::{SendOrder, BATCH_ID_SIZE};
+use crossbeam_channel::RecvTimeoutError;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use raptorq::{Decoder, Encoder, EncodingPacket, ObjectTransmissionInformation, PayloadId};
+use std::collections::hash_map::DefaultHasher;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::io::Read;
+use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{fs, str};
 
 /// Maximum over-the-wire size of a Transaction
@@ -25,6 +38,13 @@ const FLAGS: usize = 1;
 ///   8 bytes is the size of the fragment header
 const PAYLOAD_SIZE: usize = MTU_SIZE - PACKET_SNO - BATCH_ID_SIZE - FLAGS - 40 - 8;
 
+/// Size of the header in front of the serialized RaptorQ packet: the empty byte, the forward flag
+/// and the batch id
+const HEADER_SIZE: usize = BATCH_ID_SIZE + 2;
+
+/// How often the decoder workers look for batches that exceeded the idle timeout
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 /// It takes a batch id, a sequence number, and a payload, and returns a packet
 ///
 /// Arguments:
@@ -35,72 +55,210 @@ const PAYLOAD_SIZE: usize = MTU_SIZE - PACKET_SNO - BATCH_ID_SIZE - FLAGS - 40 -
 /// Returns:
 ///
 /// A vector of bytes
-pub fn create_packet(batch_id: [u8; BATCH_ID_SIZE], payload: Vec<u8>) -> Vec<u8> {
-    let mut mtu: Vec<u8> = vec![];
+pub fn create_packet(batch_id: [u8; BATCH_ID_SIZE], payload: &[u8]) -> Vec<u8> {
+    let mut mtu: Vec<u8> = Vec::with_capacity(BATCH_ID_SIZE + 2 + payload.len());
 
     // empty byte for raptor coding length
     // doing the plus one since raptor is returning minus 1 length.
 
     mtu.push(0_u8);
     // forward-flag at the beginning
-    mtu.push(1_u8);
+    mtu.push(FLAG_DIRECT);
 
     for i in 0..BATCH_ID_SIZE {
         mtu.push(batch_id[i]);
     }
-    mtu.extend_from_slice(&payload);
+    mtu.extend_from_slice(payload);
 
     mtu
 }
 
-/// `split_into_packets` takes a `full_list` of bytes, a `batch_id` and an `erasure_count` and returns a
-/// `Vec<Vec<u8>>` of packets
+/// The encoded symbols of a payload, kept apart so the sender can choose how to order them on the
+/// wire
+pub struct EncodedPackets {
+    /// Encoder configuration the receivers need to build their decoders
+    pub config: ObjectTransmissionInformation,
+    /// Serialized source symbols, in block order
+    pub source: Vec<Vec<u8>>,
+    /// Serialized repair symbols, in block order
+    pub repair: Vec<Vec<u8>>,
+}
+
+/// `split_into_packets` takes the already encoded symbols of a payload and a `batch_id` and returns
+/// a `Vec<Vec<u8>>` of packets stamped with the batch header, ordered according to `send_order`.
+/// The same `EncodedPackets` can be stamped for every batch of an identical payload, so the
+/// RaptorQ encoding only has to run once.
 ///
 /// Arguments:
 ///
-/// * `full_list`: The list of bytes to be split into packets
+/// * `encoded`: The encoded source and repair symbols of the payload.
 /// * `batch_id`: This is a unique identifier for the batch of packets.
-/// * `erasure_count`: The number of packets that can be lost and still be able to recover the original data.
-pub fn split_into_packets(
-    full_list: &[u8],
+/// * `send_order`: The order in which the packets should be sent.
+/// * `rng`: Seeded random number generator used by `SendOrder::Random`.
+pub fn split_into_packets<R: Rng>(
+    encoded: &EncodedPackets,
     batch_id: [u8; BATCH_ID_SIZE],
-    erasure_count: u32,
+    send_order: SendOrder,
+    rng: &mut R,
 ) -> Vec<Vec<u8>> {
-    let packet_holder = encode_into_packets(full_list, erasure_count);
-
-    let mut headered_packets: Vec<Vec<u8>> = vec![];
-    for (_, ep) in packet_holder.into_iter().enumerate() {
-        headered_packets.push(create_packet(batch_id, ep))
-    }
+    let headered_packets: Vec<Vec<u8>> = order_packets(encoded, send_order, rng)
+        .into_iter()
+        .map(|ep| create_packet(batch_id, ep))
+        .collect();
     println!("Packets len {:?}", headered_packets.len());
     headered_packets
 }
 
-/// It takes a list of bytes and an erasure count, and returns a list of packets
+/// It arranges the encoded symbols of a single batch in the order they should be sent.
+/// Interleaving across batches happens later, once the packets of concurrent batches are known.
+///
+/// Arguments:
+///
+/// * `packets`: The encoded source and repair symbols.
+/// * `send_order`: The order in which the packets should be sent.
+/// * `rng`: Seeded random number generator used by `SendOrder::Random`.
+///
+/// Returns:
+///
+/// The encoded symbols in transmission order.
+pub fn order_packets<'a, R: Rng>(
+    packets: &'a EncodedPackets,
+    send_order: SendOrder,
+    rng: &mut R,
+) -> Vec<&'a [u8]> {
+    let all = packets.source.iter().chain(packets.repair.iter());
+    match send_order {
+        SendOrder::SourceThenRepair | SendOrder::Interleaved => {
+            all.map(|packet| packet.as_slice()).collect()
+        }
+        SendOrder::RepairOnly => packets
+            .repair
+            .iter()
+            .map(|packet| packet.as_slice())
+            .collect(),
+        SendOrder::Random => {
+            let mut ordered: Vec<&[u8]> = all.map(|packet| packet.as_slice()).collect();
+            ordered.shuffle(rng);
+            ordered
+        }
+    }
+}
+
+/// It merges the packet lists of batches that are sent concurrently, taking one packet from each
+/// batch in turn until every list is exhausted
+///
+/// Arguments:
+///
+/// * `packet_lists`: The packet lists of the concurrent batches.
+///
+/// Returns:
+///
+/// A single vector of packets in transmission order.
+pub fn interleave_packets(packet_lists: Vec<Vec<Vec<u8>>>) -> Vec<Vec<u8>> {
+    let total = packet_lists.iter().map(|list| list.len()).sum();
+    let mut interleaved = Vec::with_capacity(total);
+    let mut iters: Vec<_> = packet_lists
+        .into_iter()
+        .map(|list| list.into_iter())
+        .collect();
+    while interleaved.len() < total {
+        for iter in iters.iter_mut() {
+            if let Some(packet) = iter.next() {
+                interleaved.push(packet);
+            }
+        }
+    }
+    interleaved
+}
+
+/// It takes a list of bytes and an erasure count, and returns the encoded source and repair packets
 ///
 /// Arguments:
 ///
 /// * `unencoded_packet_list`: This is the list of packets that we want to encode.
 /// * `erasure_count`: The number of packets that can be lost and still be able to recover the original
-/// data.
+///   data.
 ///
 /// Returns:
 ///
-/// A vector of vectors of bytes.
-pub fn encode_into_packets(unencoded_packet_list: &[u8], erasure_count: u32) -> Vec<Vec<u8>> {
-    let encoder = Encoder::with_defaults(unencoded_packet_list, (PAYLOAD_SIZE) as u16);
-    let packets: Vec<Vec<u8>> = encoder
-        .get_encoded_packets(erasure_count)
-        .iter()
-        .map(|packet| packet.serialize())
-        .collect();
+/// The serialized source and repair packets.
+pub fn encode_into_packets(unencoded_packet_list: &[u8], erasure_count: u32) -> EncodedPackets {
+    let packets =
+        encode_with_symbol_size(unencoded_packet_list, PAYLOAD_SIZE as u16, erasure_count);
 
     //Have to be transmitter to peers
-    //println!("encoder config {:?}", encoder.get_config());
-    println!("Packet size after raptor: {}", packets[0].len());
+    //println!("encoder config {:?}", packets.config);
+    println!("Packet size after raptor: {}", packets.source[0].len());
+    packets
+}
+
+/// It encodes a list of bytes with the given maximum symbol size, generating `erasure_count` repair
+/// packets for every source block
+///
+/// Arguments:
+///
+/// * `unencoded_packet_list`: This is the list of packets that we want to encode.
+/// * `symbol_size`: Maximum size of an encoded symbol, rounded down to the symbol alignment.
+/// * `erasure_count`: Number of repair packets generated per source block.
+///
+/// Returns:
+///
+/// The serialized source and repair packets along with the encoder configuration.
+pub fn encode_with_symbol_size(
+    unencoded_packet_list: &[u8],
+    symbol_size: u16,
+    erasure_count: u32,
+) -> EncodedPackets {
+    let encoder = Encoder::with_defaults(unencoded_packet_list, symbol_size);
+    let mut packets = EncodedPackets {
+        config: encoder.get_config(),
+        source: vec![],
+        repair: vec![],
+    };
+    for block in encoder.get_block_encoders() {
+        packets.source.extend(
+            block
+                .source_packets()
+                .iter()
+                .map(|packet| packet.serialize()),
+        );
+        packets.repair.extend(
+            block
+                .repair_packets(0, erasure_count)
+                .iter()
+                .map(|packet| packet.serialize()),
+        );
+    }
     packets
 }
 
+/// RaptorQ configuration the receivers build their decoders from.
+/// It has to match the configuration the sender encoded the payload with.
+pub fn decoder_config() -> ObjectTransmissionInformation {
+    ObjectTransmissionInformation::new(7038895, 1176, 1, 1, 8)
+}
+
+/// Number of source symbols of an object, the minimum number of symbols needed to decode it
+pub fn source_symbol_count(config: &ObjectTransmissionInformation) -> u64 {
+    config
+        .transfer_length()
+        .div_ceil(config.symbol_size() as u64)
+}
+
+/// It feeds a single serialized encoding packet into the decoder of its batch
+///
+/// Arguments:
+///
+/// * `decoder`: The decoder of the batch the packet belongs to.
+/// * `payload`: The serialized RaptorQ packet, without the batch header.
+///
+/// Returns:
+///
+/// The decoded bytes once enough packets have been received, otherwise `None`
+pub fn decode_packet(decoder: &mut Decoder, payload: &[u8]) -> Option<Vec<u8>> {
+    decoder.decode(EncodingPacket::deserialize(payload))
+}
+
 /// It takes a packet and returns the batch id
 ///
 /// Arguments:
@@ -110,7 +268,7 @@ pub fn encode_into_packets(unencoded_packet_list: &[u8], erasure_count: u32) ->
 /// Returns:
 ///
 /// The batch_id is being returned.
-pub fn get_batch_id(packet: &[u8; 1280]) -> [u8; BATCH_ID_SIZE] {
+pub fn get_batch_id(packet: &[u8]) -> [u8; BATCH_ID_SIZE] {
     let mut batch_id: [u8; BATCH_ID_SIZE] = [0; BATCH_ID_SIZE];
     let mut chunk_no: usize = 0;
     for i in 2..(BATCH_ID_SIZE + 2) {
@@ -120,17 +278,94 @@ pub fn get_batch_id(packet: &[u8; 1280]) -> [u8; BATCH_ID_SIZE] {
     batch_id
 }
 
-/// > Generate a random 46 byte batch id
-pub fn generate_46b_batch_id() -> [u8; BATCH_ID_SIZE] {
-    let mut x = [0_u8; BATCH_ID_SIZE];
-    thread_rng().fill_bytes(&mut x);
-    let s: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(BATCH_ID_SIZE)
-        .map(char::from)
-        .collect();
-    x.copy_from_slice(s.as_bytes());
-    x
+/// It takes a packet whose length has been checked and returns the payload id of its symbol
+pub fn get_payload_id(packet: &[u8]) -> PayloadId {
+    let mut payload_id = [0; PACKET_SNO];
+    payload_id.copy_from_slice(&packet[HEADER_SIZE..HEADER_SIZE + PACKET_SNO]);
+    PayloadId::deserialize(&payload_id)
+}
+
+/// It checks that a received datagram follows the packet layout and carries a symbol the decoders
+/// can use, before anything else reads it
+///
+/// Arguments:
+///
+/// * `packet`: The received bytes, header included.
+/// * `config`: The configuration of the receiver decoders.
+///
+/// Returns:
+///
+/// The batch id and the payload id of the packet
+pub fn validate_packet(
+    packet: &[u8],
+    config: &ObjectTransmissionInformation,
+) -> Result<([u8; BATCH_ID_SIZE], PayloadId), Error> {
+    if packet.len() < HEADER_SIZE + PACKET_SNO {
+        return Err(Error::MalformedPacket(format!(
+            "{} bytes is shorter than the packet header",
+            packet.len()
+        )));
+    }
+    if packet[0] != 0 {
+        return Err(Error::MalformedPacket(format!(
+            "unexpected leading byte {}",
+            packet[0]
+        )));
+    }
+    if packet[1] > FLAG_RELAYED {
+        return Err(Error::MalformedPacket(format!(
+            "invalid forward flag {}",
+            packet[1]
+        )));
+    }
+    let mut batch_id = [0; BATCH_ID_SIZE];
+    batch_id.copy_from_slice(&packet[2..HEADER_SIZE]);
+    if !batch_id.iter().all(u8::is_ascii_alphanumeric) {
+        return Err(Error::MalformedPacket(
+            "batch id is not alphanumeric".to_string(),
+        ));
+    }
+
+    let payload_id = get_payload_id(packet);
+    let symbol_size = packet.len() - HEADER_SIZE - PACKET_SNO;
+    if symbol_size != config.symbol_size() as usize {
+        return Err(Error::Decode(format!(
+            "symbol of {} bytes, expected {}",
+            symbol_size,
+            config.symbol_size()
+        )));
+    }
+    if payload_id.source_block_number() >= config.source_blocks() {
+        return Err(Error::Decode(format!(
+            "source block {} of {}",
+            payload_id.source_block_number(),
+            config.source_blocks()
+        )));
+    }
+    Ok((batch_id, payload_id))
+}
+
+/// It renders a batch id for logs and file names. Received ids are checked to be alphanumeric by
+/// `validate_packet`, the placeholder is only a fallback.
+pub fn batch_name(batch_id: &[u8; BATCH_ID_SIZE]) -> &str {
+    str::from_utf8(batch_id).unwrap_or("<non-utf8>")
+}
+
+/// It picks the decoder worker responsible for a batch, so that every packet of the batch lands on
+/// the same worker
+///
+/// Arguments:
+///
+/// * `batch_id`: The batch id of the packet.
+/// * `workers`: The number of decoder workers.
+///
+/// Returns:
+///
+/// The index of the worker owning the batch
+pub fn decoder_worker_index(batch_id: &[u8; BATCH_ID_SIZE], workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    batch_id.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
 }
 
 /// It reads the contents of a file into a byte array
@@ -138,114 +373,338 @@ pub fn generate_46b_batch_id() -> [u8; BATCH_ID_SIZE] {
 /// Arguments:
 ///
 /// * `file_path`: The path to the file you want to read.
-pub fn read_file(file_path: PathBuf) -> Vec<u8> {
-    let metadata = fs::metadata(&file_path).expect("unable to read metadata");
+pub fn read_file(file_path: PathBuf) -> Result<Vec<u8>, Error> {
+    let metadata = fs::metadata(&file_path)?;
     let mut buffer = vec![0; metadata.len() as usize];
-    File::open(file_path)
-        .unwrap()
-        .read_exact(&mut buffer)
-        .expect("buffer overflow");
-    buffer
+    File::open(file_path)?.read_exact(&mut buffer)?;
+    Ok(buffer)
 }
 
-/// It receives a tuple of a string and a vector of bytes from a channel, and writes the vector of bytes
-/// to a file whose name is the string
+/// It receives decoded batches from a channel and hands them to the output sink, until every
+/// decoder worker is gone and the channel is drained
 ///
 /// Arguments:
 ///
-/// * `file_recv`: Receiver<(String, Vec<u8>)>
-pub fn file_writer(file_recv: Receiver<(String, Vec<u8>)>) {
-    loop {
-        match file_recv.recv() {
-            Ok((batch_id, contents)) => {
-                let batch_fname = format!("{}.BATCH", batch_id);
-                fs::write(batch_fname, &contents).unwrap();
-            }
-            Err(e) => {
-                println!("{}", e);
-                continue;
-            }
-        };
+/// * `file_recv`: StageReceiver<DecodedBatch>
+/// * `sink`: Where the decoded batches go
+/// * `stats`: Receiver statistics, counting the failed writes
+pub fn file_writer(
+    file_recv: StageReceiver<DecodedBatch>,
+    mut sink: Box<dyn OutputSink>,
+    stats: Arc<ReceiverStats>,
+) {
+    while let Ok(batch) = file_recv.recv() {
+        if let Err(e) = sink.write_batch(&batch).and_then(|_| sink.flush()) {
+            let e = Error::from(e);
+            println!("Batch: {}: Failed to write output: {}", batch.batch_id, e);
+            stats.record_error(&e);
+        }
+    }
+    if let Err(e) = sink.flush() {
+        let e = Error::from(e);
+        println!("Failed to flush output: {}", e);
+        stats.record_error(&e);
     }
 }
 
-/// It receives packets from the `receiver` channel, checks if the packet is a duplicate, and if not, it
-/// checks if the packet is a forwarder packet. If it is, it forwards the packet to the `forwarder`
-/// channel. If it is not, it checks if the packet is a new batch. If it is, it creates a new decoder
-/// for the batch. If it is not, it adds the packet to the decoder. If the decoder is complete, it sends
-/// the decoded file to the `file_send` channel
+/// It receives packets from the `receiver` channel and checks if the packet is a forwarder packet. If
+/// it is, it forwards the packet to the `forwarder` channel. Packets of already reassembled batches
+/// are then dropped. Every symbol not seen before for the batch is then fed to the
+/// batch decoder, which is created on the first packet of the batch. If the decoder is complete, it
+/// sends the decoded file to the `file_send` channel. Datagrams that fail `validate_packet` are
+/// counted per source address and dropped without being forwarded. It returns once the `receiver` channel is
+/// disconnected and drained
 ///
 /// Arguments:
 ///
-/// * `receiver`: Receiver<([u8; 1280], usize)>
-/// * `batch_id_hashset`: A hashset that contains the batch_ids of all the batches that have been
-/// reassembled.
-/// * `decoder_hash`: A hashmap that stores the batch_id as the key and a tuple of the number of packets
-/// received and the decoder as the value.
-/// * `forwarder`: Sender<Vec<u8>>
-/// * `file_send`: Sender<(String, Vec<u8>)>
+/// * `receiver`: StageReceiver<([u8; 1280], usize, SocketAddr)>
+/// * `completed_batches`: The ids of the batches reassembled within the retention window.
+/// * `decoders`: The bounded tables of decoders of the batches still in flight, one per sender.
+/// * `forwarder`: StageSender<Forward>, also taking the have batch notice of every decoded batch
+/// * `file_send`: StageSender<DecodedBatch>
+/// * `progress`: Tracker recording the decode progress of every in-flight batch
+/// * `stats`: Receiver statistics shared with the rest of the pipeline
+/// * `acks`: Sends the acknowledgement of every decoded batch to its sender, `None` on the nodes
+///   that do not acknowledge
+/// * `reencoder`: Takes the payload of every decoded batch to mint repair symbols from, if enabled
+#[allow(clippy::too_many_arguments)]
 pub fn reassemble_packets(
-    receiver: Receiver<([u8; 1280], usize)>,
-    batch_id_hashset: &mut HashSet<[u8; BATCH_ID_SIZE]>,
-    decoder_hash: &mut HashMap<[u8; BATCH_ID_SIZE], (usize, Decoder)>,
-    forwarder: Sender<Vec<u8>>,
-    file_send: Sender<(String, Vec<u8>)>,
+    receiver: StageReceiver<([u8; 1280], usize, SocketAddr)>,
+    completed_batches: &mut CompletedBatches,
+    decoders: &mut SenderDecoders,
+    forwarder: StageSender<Forward>,
+    file_send: StageSender<DecodedBatch>,
+    progress: ProgressTracker,
+    stats: Arc<ReceiverStats>,
+    acks: Option<AckSender>,
+    reencoder: Option<StageSender<([u8; BATCH_ID_SIZE], Vec<u8>)>>,
 ) {
+    let config = decoder_config();
+    let source_symbols = source_symbol_count(&config);
+    let mut evicted = vec![];
+    let mut last_idle_check = Instant::now();
     loop {
-        let mut received_packet = match receiver.recv() {
+        // Idle batches and expired completed ids are looked for at most once per second, and even
+        // when no packet arrives
+        if last_idle_check.elapsed() >= IDLE_CHECK_INTERVAL {
+            evicted.extend(decoders.evict_idle());
+            completed_batches.expire();
+            last_idle_check = Instant::now();
+        }
+        for eviction in evicted.drain(..) {
+            log_eviction(&eviction, progress.remove(&eviction.batch_id), &stats);
+        }
+
+        let received_packet = match receiver.recv_timeout(IDLE_CHECK_INTERVAL) {
             Ok(pr) => pr,
+            Err(RecvTimeoutError::Timeout) => continue,
+            // The receive loop stopped and every queued packet has been handled
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        ReceiverStats::increment(&stats.packets_received);
+        ReceiverStats::add(&stats.bytes_received, received_packet.1 as u64);
+
+        let (batch_id, payload_id) =
+            match validate_packet(&received_packet.0[..received_packet.1], &config) {
+                Ok(ids) => ids,
+                Err(e) => {
+                    if stats.record_rejection(received_packet.2, &e) {
+                        println!("Rejected datagram from {}: {}", received_packet.2, e);
+                    }
+                    continue;
+                }
+            };
+        let namespace = SenderNamespace::of_batch(&batch_id);
+        stats.record_sender(namespace, |sender| sender.packets_received += 1);
+        // This is to check if the packet is a forwarder packet. If it is, it forwards the packet to the `forwarder` channel,
+        // which picks the peers and the forward flag according to the retransmit mode. Peers may still need the packets
+        // of a batch this node already decoded, so they are forwarded before the completed check.
+        if received_packet.0[1] != FLAG_FORWARDED {
+            let _ = forwarder.send(Forward::Packet(
+                received_packet.0[0..received_packet.1].to_vec(),
+            ));
+        }
+
+        if completed_batches.contains(&batch_id) {
+            continue;
+        }
+
+        // The first packet of a batch creates its decoder and is decoded like any other, the
+        // decoder table drops the symbols it already has
+        let decoded = match decoders.decode(
+            batch_id,
+            (
+                payload_id.source_block_number(),
+                payload_id.encoding_symbol_id(),
+            ),
+            &received_packet.0[48_usize..received_packet.1],
+            &mut evicted,
+        ) {
+            Ok(decoded) => decoded,
             Err(e) => {
-                println!("{}", e);
+                if stats.record_rejection(received_packet.2, &e) {
+                    println!("Rejected datagram from {}: {}", received_packet.2, e);
+                }
                 continue;
             }
         };
+        let unique_symbols = match &decoded {
+            Some((num_packets, _)) => *num_packets,
+            None => decoders.unique_symbols(&batch_id),
+        };
+        progress.record(
+            batch_id,
+            received_packet.2,
+            received_packet.0[1] != FLAG_DIRECT,
+            source_symbols,
+            unique_symbols,
+        );
+        if let Some((num_packets, result_bytes)) = decoded {
+            completed_batches.insert(batch_id);
+            // Peers stop forwarding the packets of the batch to this node once they get the notice
+            let _ = forwarder.send(Forward::Have(batch_id));
+            ReceiverStats::increment(&stats.batches_decoded);
+            ReceiverStats::add(&stats.bytes_decoded, result_bytes.len() as u64);
+            stats.record_sender(namespace, |sender| {
+                sender.batches_decoded += 1;
+                sender.bytes_decoded += result_bytes.len() as u64;
+            });
 
-        let batch_id = get_batch_id(&received_packet.0);
-        if batch_id_hashset.contains(&batch_id) {
-            continue;
+            println!(
+                "Batch: {}: Generating reassembled file: {:?}: Number of packets received: {}: Minimum: {}: Overhead: {}",
+                batch_name(&batch_id),
+                SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+                num_packets,
+                source_symbols,
+                num_packets as i64 - source_symbols as i64
+            );
+            let batch_progress = progress.remove(&batch_id);
+            let decode_time = batch_progress
+                .as_ref()
+                .and_then(|p| SystemTime::now().duration_since(p.first_packet).ok())
+                .unwrap_or_default();
+            let (packets_received, senders, forwarders) = batch_progress
+                .as_ref()
+                .map(|p| (p.packets_received, p.senders.len(), p.forwarders.len()))
+                .unwrap_or_default();
+            if let Some(acks) = &acks {
+                for sender in batch_progress.iter().flat_map(|p| &p.senders) {
+                    if let Err(e) = acks.send(batch_id, decode_time, num_packets, *sender) {
+                        stats.record_error(&e);
+                    }
+                }
+            }
+            println!(
+                "Batch: {}: Packets including duplicates: {}: Senders: {}: Forwarders: {}: Decoded in {:?}",
+                batch_name(&batch_id),
+                packets_received,
+                senders,
+                forwarders,
+                decode_time
+            );
+            if let Some(reencoder) = &reencoder {
+                let _ = reencoder.send((batch_id, result_bytes.clone()));
+            }
+            // This is the part of the code that is sending the reassembled file to the `file_send` channel.
+            let msg = DecodedBatch {
+                batch_id: batch_name(&batch_id).to_string(),
+                contents: result_bytes,
+                num_packets,
+                packets_received,
+                senders,
+                forwarders,
+                decode_time,
+                decoded_at: SystemTime::now(),
+            };
+            let _ = file_send.send(msg);
         }
+    }
+}
 
-        // This is to check if the packet is a forwarder packet. If it is, it forwards the packet to the `forwarder` channel.
-        // Since packet is shared across nodes with forward flag as 1
-        let forward_flag = received_packet.0.get_mut(1).unwrap();
-        if *forward_flag == 1 {
-            *forward_flag = 0;
-            let _ = forwarder.try_send(received_packet.0[0..received_packet.1].to_vec());
-        }
+/// `relay_packets` passes the packets of the `receiver` channel on to the `forwarder` without
+/// decoding them, which is all a relay does. Datagrams that fail `validate_packet` are counted per
+/// source address and dropped without being forwarded. It returns once the `receiver` channel is
+/// disconnected and drained
+///
+/// Arguments:
+///
+/// * `receiver`: StageReceiver<([u8; 1280], usize, SocketAddr)>
+/// * `forwarder`: StageSender<Forward>
+/// * `stats`: Receiver statistics shared with the rest of the pipeline
+pub fn relay_packets(
+    receiver: StageReceiver<([u8; 1280], usize, SocketAddr)>,
+    forwarder: StageSender<Forward>,
+    stats: Arc<ReceiverStats>,
+) {
+    let config = decoder_config();
+    while let Ok((packet, len, from)) = receiver.recv() {
+        ReceiverStats::increment(&stats.packets_received);
+        ReceiverStats::add(&stats.bytes_received, len as u64);
 
-        match decoder_hash.get_mut(&batch_id) {
-            Some((num_packets, decoder)) => {
-                *num_packets += 1;
-                // Decoding the packet.
-                let result = decoder.decode(EncodingPacket::deserialize(
-                    &received_packet.0[48_usize..received_packet.1].to_vec(),
-                ));
-                if !result.is_none() {
-                    batch_id_hashset.insert(batch_id);
-
-                    println!(
-                        "Batch: {}: Generating reassembled file: {:?}: Number of packets received: {}",
-                        str::from_utf8(&batch_id).unwrap(),
-                        SystemTime::now().duration_since(UNIX_EPOCH).unwrap(), *num_packets
-                    );
-                    // This is the part of the code that is sending the reassembled file to the `file_send` channel.
-                    let result_bytes = result.unwrap();
-                    let batch_id_str = String::from(str::from_utf8(&batch_id).unwrap());
-                    let msg = (batch_id_str, result_bytes);
-                    let _ = file_send.send(msg);
-                    decoder_hash.remove(&batch_id);
+        let batch_id = match validate_packet(&packet[..len], &config) {
+            Ok((batch_id, _)) => batch_id,
+            Err(e) => {
+                if stats.record_rejection(from, &e) {
+                    println!("Rejected datagram from {}: {}", from, e);
                 }
+                continue;
             }
-            None => {
-                // This is creating a new decoder for a new batch.
-                decoder_hash.insert(
-                    batch_id,
-                    (
-                        1_usize,
-                        Decoder::new(ObjectTransmissionInformation::new(7038895, 1176, 1, 1, 8)),
-                    ),
-                );
-            }
+        };
+        stats.record_sender(SenderNamespace::of_batch(&batch_id), |sender| {
+            sender.packets_received += 1
+        });
+        if packet[1] != FLAG_FORWARDED {
+            let _ = forwarder.send(Forward::Packet(packet[..len].to_vec()));
         }
     }
 }
+
+/// It reports a batch dropped from the decoder table along with the progress it had made
+fn log_eviction(eviction: &Eviction, progress: Option<BatchProgress>, stats: &ReceiverStats) {
+    ReceiverStats::increment(&stats.batches_evicted);
+    stats.record_sender(SenderNamespace::of_batch(&eviction.batch_id), |sender| {
+        sender.batches_evicted += 1
+    });
+    let (senders, forwarders, packets) = progress
+        .map(|p| (p.senders.len(), p.forwarders.len(), p.packets_received))
+        .unwrap_or_default();
+    println!(
+        "Batch: {}: Evicted ({}): symbols {} of {}: packets {}: senders {}: forwarders {}: idle for {:?}",
+        batch_name(&eviction.batch_id),
+        eviction.reason,
+        eviction.num_packets,
+        source_symbol_count(&decoder_config()),
+        packets,
+        senders,
+        forwarders,
+        eviction.idle
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::have::HAVE_KIND;
+
+    /// A data packet of batch `aaa…` carrying a symbol of `symbol_size` bytes
+    fn packet(source_block: u8, symbol_size: usize) -> Vec<u8> {
+        let symbol = EncodingPacket::new(PayloadId::new(source_block, 5), vec![0; symbol_size]);
+        create_packet([b'a'; BATCH_ID_SIZE], &symbol.serialize())
+    }
+
+    #[test]
+    fn validate_packet_accepts_a_data_packet() {
+        let config = decoder_config();
+        let (batch_id, payload_id) =
+            validate_packet(&packet(0, config.symbol_size() as usize), &config).unwrap();
+        assert_eq!(batch_id, [b'a'; BATCH_ID_SIZE]);
+        assert_eq!(payload_id.encoding_symbol_id(), 5);
+    }
+
+    #[test]
+    fn validate_packet_rejects_malformed_headers() {
+        let config = decoder_config();
+        let valid = packet(0, config.symbol_size() as usize);
+
+        let short = &valid[..HEADER_SIZE + PACKET_SNO - 1];
+        assert!(matches!(
+            validate_packet(short, &config),
+            Err(Error::MalformedPacket(_))
+        ));
+
+        let mut kind = valid.clone();
+        kind[0] = HAVE_KIND;
+        assert!(matches!(
+            validate_packet(&kind, &config),
+            Err(Error::MalformedPacket(_))
+        ));
+
+        let mut flag = valid.clone();
+        flag[1] = FLAG_RELAYED + 1;
+        assert!(matches!(
+            validate_packet(&flag, &config),
+            Err(Error::MalformedPacket(_))
+        ));
+
+        let mut batch_id = valid;
+        batch_id[HEADER_SIZE - 1] = b'/';
+        assert!(matches!(
+            validate_packet(&batch_id, &config),
+            Err(Error::MalformedPacket(_))
+        ));
+    }
+
+    #[test]
+    fn validate_packet_rejects_symbols_outside_the_oti() {
+        let config = decoder_config();
+        let symbol_size = config.symbol_size() as usize;
+        assert!(matches!(
+            validate_packet(&packet(0, symbol_size - 1), &config),
+            Err(Error::Decode(_))
+        ));
+        assert!(matches!(
+            validate_packet(&packet(config.source_blocks(), symbol_size), &config),
+            Err(Error::Decode(_))
+        ));
+    }
+}